@@ -0,0 +1,544 @@
+//! Reads the commit-graph file that `git commit-graph write` or `git gc` leaves in
+//! `objects/info`. It has the parents, the commit times and the generation numbers of commits,
+//! so the history can be walked without reading commit objects, and a walk can stop at commits
+//! too old to matter. The format is described in `Documentation/gitformat-commit-graph.txt` of
+//! git. Only a single file is read, not a chain of split graphs.
+
+use anyhow::{anyhow, Result};
+use git2::{Oid, Repository};
+use std::{
+    collections::{BinaryHeap, HashMap},
+    convert::TryInto,
+    fs,
+    path::PathBuf,
+};
+
+const SIGNATURE: &[u8] = b"CGPH";
+const OID_LEN: usize = 20;
+/// The size of the fan-out table, which has the number of commits up to each first byte.
+const FANOUT_LEN: usize = 256 * 4;
+/// The size of a commit in the data chunk: the tree, two parents, the generation and the time.
+const COMMIT_DATA_LEN: usize = OID_LEN + 16;
+const NO_PARENT: u32 = 0x7000_0000;
+/// Set on the second parent if the rest of the parents are in the extra edges chunk.
+const EXTRA_EDGES: u32 = 0x8000_0000;
+/// Set on the last parent in the extra edges chunk.
+const LAST_EDGE: u32 = 0x8000_0000;
+
+pub(crate) struct CommitGraph {
+    data: Vec<u8>,
+    len: usize,
+    fanout: usize,
+    oids: usize,
+    commits: usize,
+    edges: Option<(usize, usize)>,
+}
+
+impl CommitGraph {
+    /// Reads the commit-graph of the repository, failing if it has none or it cannot be used.
+    pub(crate) fn open(repo: &Repository) -> Result<Self> {
+        // A worktree has the objects in the common directory of the main repository.
+        let git_dir = repo.path();
+        let objects = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(common) => git_dir.join(common.trim()).join("objects"),
+            Err(_) => git_dir.join("objects"),
+        };
+        let path: PathBuf = objects.join("info").join("commit-graph");
+        let data = fs::read(&path).map_err(|e| anyhow!("No commit-graph at {:?}: {}", path, e))?;
+        Self::parse(data)
+    }
+
+    fn parse(data: Vec<u8>) -> Result<Self> {
+        if data.len() < 8 || &data[..4] != SIGNATURE {
+            return Err(anyhow!("Not a commit-graph file"));
+        }
+        // The version of the format, the hash (1 is SHA-1), the number of chunks and of the
+        // base graphs of a split graph.
+        match (data[4], data[5], data[7]) {
+            (1, 1, 0) => (),
+            (1, 1, _) => return Err(anyhow!("Split commit-graphs are not supported")),
+            (version, hash, _) => {
+                return Err(anyhow!(
+                    "Unsupported commit-graph version {} with hash version {}",
+                    version,
+                    hash
+                ))
+            }
+        }
+        let chunk_count = data[6] as usize;
+        let table_end = 8 + (chunk_count + 1) * 12;
+        if data.len() < table_end {
+            return Err(anyhow!("Truncated commit-graph file"));
+        }
+        // Each chunk ends where the next one starts, and the table ends with the end of the last.
+        let mut chunks = HashMap::new();
+        for i in 0..chunk_count {
+            let entry = 8 + i * 12;
+            let start = read_u64(&data, entry + 4) as usize;
+            let end = read_u64(&data, entry + 16) as usize;
+            if end < start || data.len() < end {
+                return Err(anyhow!("Invalid chunk in the commit-graph file"));
+            }
+            chunks.insert(&data[entry..entry + 4], (start, end));
+        }
+        let chunk = |id: &[u8]| {
+            chunks.get(id).copied().ok_or_else(|| {
+                anyhow!(
+                    "No {} chunk in the commit-graph file",
+                    String::from_utf8_lossy(id)
+                )
+            })
+        };
+        let (fanout, fanout_end) = chunk(b"OIDF")?;
+        if fanout_end - fanout < FANOUT_LEN {
+            return Err(anyhow!("Truncated fan-out in the commit-graph file"));
+        }
+        let len = read_u32(&data, fanout + FANOUT_LEN - 4) as usize;
+        let (oids, oids_end) = chunk(b"OIDL")?;
+        let (commits, commits_end) = chunk(b"CDAT")?;
+        if oids_end - oids < len * OID_LEN || commits_end - commits < len * COMMIT_DATA_LEN {
+            return Err(anyhow!("Truncated commits in the commit-graph file"));
+        }
+        let edges = chunk(b"EDGE").ok();
+        let graph = Self {
+            data,
+            len,
+            fanout,
+            oids,
+            commits,
+            edges,
+        };
+        // Graphs written by old git have zero for every generation, which cannot be used to tell
+        // that a commit is not an ancestor.
+        if (0..len as u32).any(|pos| graph.generation(pos) == 0) {
+            return Err(anyhow!("The commit-graph has no generation numbers"));
+        }
+        Ok(graph)
+    }
+
+    /// The number of commits in the graph.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Finds the position of a commit in the graph.
+    fn position(&self, id: Oid) -> Option<u32> {
+        let bytes = id.as_bytes();
+        let first = bytes[0] as usize;
+        let start = match first {
+            0 => 0,
+            _ => read_u32(&self.data, self.fanout + (first - 1) * 4) as usize,
+        };
+        let end = (read_u32(&self.data, self.fanout + first * 4) as usize).min(self.len);
+        let (mut low, mut high) = (start.min(end), end);
+        while low < high {
+            let mid = (low + high) / 2;
+            let oid = &self.data[self.oids + mid * OID_LEN..self.oids + (mid + 1) * OID_LEN];
+            match oid.cmp(bytes) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(mid as u32),
+            }
+        }
+        None
+    }
+
+    fn oid(&self, pos: u32) -> Option<Oid> {
+        let start = self.oids + pos as usize * OID_LEN;
+        if self.len <= pos as usize {
+            return None;
+        }
+        Oid::from_bytes(&self.data[start..start + OID_LEN]).ok()
+    }
+
+    fn commit_data(&self, pos: u32) -> usize {
+        self.commits + pos as usize * COMMIT_DATA_LEN
+    }
+
+    fn generation(&self, pos: u32) -> u32 {
+        read_u32(&self.data, self.commit_data(pos) + OID_LEN + 8) >> 2
+    }
+
+    fn time(&self, pos: u32) -> i64 {
+        let data = self.commit_data(pos) + OID_LEN + 8;
+        let high = (read_u32(&self.data, data) & 0x3) as i64;
+        (high << 32) | read_u32(&self.data, data + 4) as i64
+    }
+
+    /// The parents of a commit, or `None` if the graph refers to commits it does not have.
+    fn parents(&self, pos: u32) -> Option<Vec<Oid>> {
+        let data = self.commit_data(pos) + OID_LEN;
+        let mut parents = vec![];
+        let first = read_u32(&self.data, data);
+        if first == NO_PARENT {
+            return Some(parents);
+        }
+        parents.push(self.oid(first)?);
+        let second = read_u32(&self.data, data + 4);
+        if second == NO_PARENT {
+            return Some(parents);
+        }
+        if second & EXTRA_EDGES == 0 {
+            parents.push(self.oid(second)?);
+            return Some(parents);
+        }
+        let (edges, edges_end) = self.edges?;
+        let mut edge = edges + (second & !EXTRA_EDGES) as usize * 4;
+        loop {
+            if edges_end < edge + 4 {
+                return None;
+            }
+            let parent = read_u32(&self.data, edge);
+            parents.push(self.oid(parent & !LAST_EDGE)?);
+            if parent & LAST_EDGE != 0 {
+                return Some(parents);
+            }
+            edge += 4;
+        }
+    }
+}
+
+/// What is known of a commit reached in [`walk`].
+struct Reached {
+    /// The shortest distance from the starting commits, if it is reachable from them.
+    distance: Option<usize>,
+    /// Whether it is reachable from the hidden commits.
+    hidden: bool,
+}
+
+/// Parents, generations and times of commits from the graph, or computed from the commit
+/// objects for the commits newer than the graph.
+struct Commits<'a> {
+    repo: &'a Repository,
+    graph: &'a CommitGraph,
+    generations: HashMap<Oid, u32>,
+}
+
+impl<'a> Commits<'a> {
+    fn parents(&self, id: Oid) -> Result<Vec<Oid>> {
+        if let Some(pos) = self.graph.position(id) {
+            return self
+                .graph
+                .parents(pos)
+                .ok_or_else(|| anyhow!("Invalid parents of {} in the commit-graph", id));
+        }
+        Ok(self.repo.find_commit(id)?.parent_ids().collect())
+    }
+
+    fn time(&self, id: Oid) -> Result<i64> {
+        match self.graph.position(id) {
+            Some(pos) => Ok(self.graph.time(pos)),
+            None => Ok(self.repo.find_commit(id)?.time().seconds()),
+        }
+    }
+
+    fn known_generation(&self, id: Oid) -> Option<u32> {
+        match self.graph.position(id) {
+            Some(pos) => Some(self.graph.generation(pos)),
+            None => self.generations.get(&id).copied(),
+        }
+    }
+
+    /// The generation of a commit is one more than the largest of its parents, so a commit is
+    /// never an ancestor of one with a smaller or the same generation. Commits not in the graph
+    /// are given one in the same way, going down to the commits in the graph.
+    fn generation(&mut self, id: Oid) -> Result<u32> {
+        let mut stack = vec![id];
+        while let Some(&top) = stack.last() {
+            if self.known_generation(top).is_some() {
+                stack.pop();
+                continue;
+            }
+            let mut generation = 0;
+            let mut ready = true;
+            for parent in self.parents(top)? {
+                match self.known_generation(parent) {
+                    Some(parent) => generation = generation.max(parent),
+                    None => {
+                        ready = false;
+                        stack.push(parent);
+                    }
+                }
+            }
+            if ready {
+                self.generations.insert(top, generation + 1);
+                stack.pop();
+            }
+        }
+        Ok(self.known_generation(id).unwrap_or_default())
+    }
+}
+
+/// Lists the commits reachable from `starts` but not from `hidden`, within `depth` generations
+/// from the starts if given, in a topological order. Commits are visited from the largest
+/// generation, so that every child of a commit is visited before it and whether it is hidden is
+/// known when it is visited. The walk stops when only hidden commits are left to visit, rather
+/// than going down to their roots.
+pub(crate) fn walk(
+    repo: &Repository,
+    graph: &CommitGraph,
+    starts: &[Oid],
+    hidden: &[Oid],
+    depth: Option<usize>,
+    first_parent: bool,
+) -> Result<Vec<Oid>> {
+    let mut commits = Commits {
+        repo,
+        graph,
+        generations: HashMap::new(),
+    };
+    let mut reached: HashMap<Oid, Reached> = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    // The number of commits in the queue that are not hidden.
+    let mut interesting = 0;
+
+    let tips = starts
+        .iter()
+        .map(|id| (*id, false))
+        .chain(hidden.iter().map(|id| (*id, true)));
+    for (id, hide) in tips {
+        if !reached.contains_key(&id) {
+            queue.push((commits.generation(id)?, commits.time(id)?, id));
+        }
+        let entry = reached.entry(id).or_insert(Reached {
+            distance: None,
+            hidden: false,
+        });
+        if hide {
+            entry.hidden = true;
+        } else {
+            entry.distance = Some(0);
+        }
+    }
+    interesting += reached.values().filter(|reached| !reached.hidden).count();
+
+    let mut result = vec![];
+    while let Some((_, _, id)) = queue.pop() {
+        if interesting == 0 {
+            break;
+        }
+        let (distance, hidden) = {
+            let reached = &reached[&id];
+            (reached.distance, reached.hidden)
+        };
+        if !hidden {
+            interesting -= 1;
+        }
+        // The parents of hidden commits are all hidden, and the others are one step further.
+        let distance = if hidden {
+            None
+        } else {
+            let distance = distance.unwrap_or_default();
+            result.push(id);
+            if depth.is_some_and(|depth| depth <= distance + 1) {
+                continue;
+            }
+            Some(distance + 1)
+        };
+        let parents = commits.parents(id)?;
+        let count = if first_parent && !hidden {
+            1
+        } else {
+            parents.len()
+        };
+        for parent in parents.into_iter().take(count) {
+            let queued = reached.contains_key(&parent);
+            let entry = reached.entry(parent).or_insert(Reached {
+                distance: None,
+                hidden: false,
+            });
+            let was_interesting = queued && !entry.hidden;
+            if hidden {
+                entry.hidden = true;
+            } else if let Some(distance) = distance {
+                entry.distance = Some(entry.distance.map_or(distance, |d| d.min(distance)));
+            }
+            if !queued {
+                queue.push((commits.generation(parent)?, commits.time(parent)?, parent));
+            }
+            match (was_interesting, queued, entry.hidden) {
+                (true, _, true) => interesting -= 1,
+                (_, false, false) => interesting += 1,
+                _ => (),
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{Signature, Time};
+    use std::{collections::HashSet, process::Command};
+    use tempfile::TempDir;
+
+    /// Makes a history with an octopus merge of three branches and a merge of it, and writes
+    /// the commit-graph of it with git. The commits are a minute apart.
+    fn repo_with_graph() -> (TempDir, Repository) {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let tree = {
+            let mut index = repo.index().unwrap();
+            repo.find_tree(index.write_tree().unwrap()).unwrap().id()
+        };
+        let mut minutes = 0;
+        let mut commit = |parents: &[Oid]| {
+            minutes += 1;
+            let signature =
+                Signature::new("test", "test@example.com", &Time::new(minutes * 60, 0)).unwrap();
+            let tree = repo.find_tree(tree).unwrap();
+            let parents: Vec<_> = parents
+                .iter()
+                .map(|id| repo.find_commit(*id).unwrap())
+                .collect();
+            let parents: Vec<_> = parents.iter().collect();
+            repo.commit(None, &signature, &signature, "commit", &tree, &parents)
+                .unwrap()
+        };
+        let root = commit(&[]);
+        let a = commit(&[root]);
+        let b = commit(&[root]);
+        let c = commit(&[a]);
+        let d = commit(&[b]);
+        let octopus = commit(&[c, d, a, root]);
+        let e = commit(&[root]);
+        let head = commit(&[octopus, e]);
+        repo.branch("main", &repo.find_commit(head).unwrap(), true)
+            .unwrap();
+        repo.set_head("refs/heads/main").unwrap();
+        let status = Command::new("git")
+            .args(["commit-graph", "write", "--reachable"])
+            .current_dir(dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        (dir, repo)
+    }
+
+    fn graph_data(repo: &Repository) -> Vec<u8> {
+        fs::read(repo.path().join("objects/info/commit-graph")).unwrap()
+    }
+
+    #[test]
+    fn reads_the_commits_written_by_git() {
+        let (_dir, repo) = repo_with_graph();
+        let graph = CommitGraph::open(&repo).unwrap();
+        assert_eq!(graph.len(), 8);
+        let mut revwalk = repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        let mut octopus = false;
+        for id in revwalk {
+            let commit = repo.find_commit(id.unwrap()).unwrap();
+            let pos = graph.position(commit.id()).unwrap();
+            assert_eq!(graph.oid(pos), Some(commit.id()));
+            let parents: Vec<_> = commit.parent_ids().collect();
+            assert_eq!(graph.parents(pos).unwrap(), parents);
+            assert_eq!(graph.time(pos), commit.time().seconds());
+            let generation = parents
+                .iter()
+                .map(|parent| graph.generation(graph.position(*parent).unwrap()))
+                .max()
+                .unwrap_or_default();
+            assert!(generation < graph.generation(pos));
+            octopus |= 2 < parents.len();
+        }
+        // The parents after the first of the octopus merge are in the extra edges.
+        assert!(octopus);
+        assert!(graph.edges.is_some());
+        assert_eq!(graph.position(Oid::zero()), None);
+    }
+
+    #[test]
+    fn walks_the_same_commits_as_a_revwalk() {
+        let (_dir, repo) = repo_with_graph();
+        let graph = CommitGraph::open(&repo).unwrap();
+        let head = repo.head().unwrap().target().unwrap();
+        let commits = walk(&repo, &graph, &[head], &[], None, false).unwrap();
+        let mut revwalk = repo.revwalk().unwrap();
+        revwalk.push(head).unwrap();
+        let expected: HashSet<_> = revwalk.map(Result::unwrap).collect();
+        assert_eq!(commits.len(), expected.len());
+        assert_eq!(commits.iter().copied().collect::<HashSet<_>>(), expected);
+        // Every child comes before its parents.
+        for (i, id) in commits.iter().enumerate() {
+            for parent in repo.find_commit(*id).unwrap().parent_ids() {
+                assert!(commits[..i].iter().all(|id| *id != parent));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_commit_graphs() {
+        let (_dir, repo) = repo_with_graph();
+        let data = graph_data(&repo);
+        assert!(CommitGraph::parse(vec![]).is_err());
+        assert!(CommitGraph::parse(b"CGPH".to_vec()).is_err());
+        assert!(CommitGraph::parse([b"GPHC", &data[4..]].concat()).is_err());
+        let mut version = data.clone();
+        version[4] = 2;
+        assert!(CommitGraph::parse(version).is_err());
+        // A chunk count beyond the table reads the offsets out of the chunks.
+        let mut chunks = data;
+        chunks[6] = 0xff;
+        assert!(CommitGraph::parse(chunks).is_err());
+    }
+
+    /// Looks up every commit of the repository in the graph and walks it, which must not panic
+    /// whatever the data is.
+    fn read_all(repo: &Repository, data: Vec<u8>) {
+        if let Ok(graph) = CommitGraph::parse(data) {
+            let mut revwalk = repo.revwalk().unwrap();
+            revwalk.push_head().unwrap();
+            for id in revwalk {
+                let id = id.unwrap();
+                if let Some(pos) = graph.position(id) {
+                    graph.oid(pos);
+                    graph.parents(pos);
+                    graph.time(pos);
+                    graph.generation(pos);
+                }
+            }
+            for pos in 0..graph.len() as u32 {
+                graph.parents(pos);
+            }
+            let head = repo.head().unwrap().target().unwrap();
+            let _ = walk(repo, &graph, &[head], &[], None, false);
+        }
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let (_dir, repo) = repo_with_graph();
+        let data = graph_data(&repo);
+        // Everything but the checksum at the end is needed.
+        for len in 0..data.len() - OID_LEN {
+            assert!(
+                CommitGraph::parse(data[..len].to_vec()).is_err(),
+                "parsed {} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn corrupt_files_do_not_panic() {
+        let (_dir, repo) = repo_with_graph();
+        let data = graph_data(&repo);
+        for i in 0..data.len() {
+            for value in [0, 0x7f, 0x80, 0xff] {
+                let mut corrupt = data.clone();
+                corrupt[i] = value;
+                read_all(&repo, corrupt);
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Default options read from `.gitgreprc` files, which are in TOML like:
+//!
+//! ```toml
+//! extensions = ["md", "toml"]
+//! ignore_dirs = ["vendor"]
+//! color = "never"
+//! context = 2
+//! ```
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+const RC_FILE_NAME: &str = ".gitgreprc";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RcFile {
+    /// Extensions to search in addition to the default ones, unless given by the command line.
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// Directories to skip in addition to the default ones, unless given by the command line.
+    #[serde(default)]
+    pub ignore_dirs: Vec<String>,
+    /// The default of `--color`.
+    pub color: Option<String>,
+    /// The default of `--context`.
+    pub context: Option<usize>,
+}
+
+impl RcFile {
+    /// Loads the rc files in the home directory and in the root of `repo`, the latter of which
+    /// takes precedence for each option. Missing files are just skipped.
+    pub fn load(repo: &Path) -> Result<Self> {
+        let home = env::var_os("HOME")
+            .or_else(|| env::var_os("USERPROFILE"))
+            .map(PathBuf::from);
+        let home_rc = match home {
+            Some(home) => Self::read(&home.join(RC_FILE_NAME))?,
+            None => Self::default(),
+        };
+        let repo_rc = Self::read(&repo.join(RC_FILE_NAME))?;
+        Ok(repo_rc.or(home_rc))
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Could not read {:?}: {}", path, e))?;
+        toml::from_str(&text).map_err(|e| anyhow!("Error in {:?}: {}", path, e))
+    }
+
+    /// Takes the options of `self`, and the ones of `other` where `self` does not have them.
+    fn or(self, other: Self) -> Self {
+        Self {
+            extensions: if self.extensions.is_empty() {
+                other.extensions
+            } else {
+                self.extensions
+            },
+            ignore_dirs: if self.ignore_dirs.is_empty() {
+                other.ignore_dirs
+            } else {
+                self.ignore_dirs
+            },
+            color: self.color.or(other.color),
+            context: self.context.or(other.context),
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Output templates given by `--format`, like `{commit} {path}:{line}:{col}: {text}`.
+
+use crate::OutputSettings;
+use anyhow::{anyhow, Result};
+use rust_gitgrep::MatchEntry;
+
+/// The length of `{short_commit}` without `--abbrev`, which is the default of git.
+pub const SHORT_COMMIT_LEN: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Commit,
+    ShortCommit,
+    Path,
+    Line,
+    Col,
+    Text,
+}
+
+#[derive(Debug)]
+enum Token {
+    Literal(String),
+    Field(Field),
+}
+
+/// A parsed template. Placeholders are names in braces, and literal braces are written doubled
+/// like `{{` and `}}`, as in the format strings of Rust.
+#[derive(Debug)]
+pub struct Template {
+    tokens: Vec<Token>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut tokens = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| anyhow!("Unclosed placeholder in format: {:?}", template))?;
+                    let field = match &rest[..end] {
+                        "commit" => Field::Commit,
+                        "short_commit" => Field::ShortCommit,
+                        "path" => Field::Path,
+                        "line" => Field::Line,
+                        "col" => Field::Col,
+                        "text" => Field::Text,
+                        name => return Err(anyhow!("Unknown placeholder in format: {{{}}}", name)),
+                    };
+                    if !literal.is_empty() {
+                        tokens.push(Token::Literal(std::mem::take(&mut literal)));
+                    }
+                    tokens.push(Token::Field(field));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(anyhow!("Unmatched }} in format: {:?}", template)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal));
+        }
+        Ok(Self { tokens })
+    }
+
+    /// Renders the template for a match, where `{text}` is replaced with `text`. Commit ids are
+    /// abbreviated as `--abbrev` tells in `output`.
+    pub fn render(&self, entry: &MatchEntry, text: &str, output: &OutputSettings) -> String {
+        let mut result = String::new();
+        for token in &self.tokens {
+            match token {
+                Token::Literal(literal) => result.push_str(literal),
+                Token::Field(Field::Commit) => result.push_str(&output.commit_id(entry.commit())),
+                Token::Field(Field::ShortCommit) => {
+                    result.push_str(&output.short_commit_id(entry.commit()))
+                }
+                Token::Field(Field::Path) => result.push_str(&entry.path().to_string_lossy()),
+                Token::Field(Field::Line) => result.push_str(&entry.line_number().to_string()),
+                Token::Field(Field::Col) => result.push_str(&entry.column().to_string()),
+                Token::Field(Field::Text) => result.push_str(text),
+            }
+        }
+        result
+    }
+}
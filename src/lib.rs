@@ -0,0 +1,2519 @@
+//! Searches through all the files in all the history of a git repository.
+//!
+//! Build [`Settings`] and pass it to [`GitGrep::new`], then call [`GitGrep::search`] to collect
+//! matches, or [`GitGrep::search_with`] to receive them as they are found.
+
+mod commit_graph;
+
+use anyhow::{anyhow, Result};
+use chardetng::EncodingDetector;
+use commit_graph::CommitGraph;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE};
+use git2::{
+    Blob, Commit, Delta, DiffFindOptions, FileMode, ObjectType, Oid, Patch, Reference, Repository,
+    RevparseMode, Signature, Sort, Tree,
+};
+use globset::GlobSet;
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, trace, warn};
+use rayon::prelude::*;
+use regex::Regex;
+use std::{
+    borrow::Cow,
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        HashMap, HashSet, VecDeque,
+    },
+    ffi::OsString,
+    hash::{Hash, Hasher},
+    io::Write,
+    ops::Range,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// File extensions searched by default.
+pub const DEFAULT_EXTENSIONS: [&str; 16] = [
+    "sh", "js", "tcl", "pl", "py", "rb", "c", "cpp", "h", "rc", "rci", "dlg", "pas", "dpr", "cs",
+    "rs",
+];
+
+/// Named sets of extensions, like the file types of ripgrep. Names without a dot are whole file
+/// names, as in [`Settings::extensions`].
+pub const FILE_TYPES: &[(&str, &[&str])] = &[
+    ("c", &["c", "h"]),
+    ("cpp", &["cpp", "cc", "cxx", "hpp", "hh", "hxx", "h"]),
+    ("csharp", &["cs"]),
+    ("go", &["go"]),
+    ("java", &["java"]),
+    ("js", &["js", "mjs", "cjs", "jsx"]),
+    ("json", &["json"]),
+    ("make", &["mk", "Makefile", "makefile", "GNUmakefile"]),
+    ("markdown", &["md", "markdown"]),
+    ("pascal", &["pas", "dpr"]),
+    ("perl", &["pl", "pm"]),
+    ("python", &["py", "pyi"]),
+    ("rc", &["rc", "rci", "dlg"]),
+    ("ruby", &["rb"]),
+    ("rust", &["rs"]),
+    ("sh", &["sh", "bash", "zsh"]),
+    ("tcl", &["tcl"]),
+    ("toml", &["toml"]),
+    ("ts", &["ts", "tsx"]),
+    ("web", &["html", "htm", "css", "js"]),
+    ("yaml", &["yml", "yaml"]),
+];
+
+/// Names of entries ignored by default.
+// Probably we could ignore all directories beginning with a dot.
+pub const DEFAULT_IGNORE_DIRS: [&str; 6] =
+    [".hg", ".svn", ".git", ".bzr", "node_modules", "target"];
+
+/// The path that matches in commit messages are reported with.
+pub const COMMIT_MESSAGE_PATH: &str = "<commit message>";
+
+/// The fewest blobs of a tree searched by a worker, which has to open the repository first.
+const MIN_BLOBS_PER_TASK: usize = 32;
+
+/// How to search files that look binary, which are the ones with NULs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryFiles {
+    /// Skip binary files.
+    WithoutMatch,
+    /// Search binary files like text files.
+    Text,
+    /// Search binary files, but report only one match without the line for each of them.
+    Binary,
+}
+
+/// The order to walk commits in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommitOrder {
+    /// Children before their parents, and newer commits first among the others, like
+    /// `git log --topo-order`.
+    Topological,
+    /// Newer commits first by the commit time, like `git log --date-order` but without keeping
+    /// children before their parents if the clocks were skewed.
+    Date,
+    /// Older commits first by the commit time.
+    ReverseDate,
+}
+
+/// How the line of a match was changed by the commit, with [`Settings::diff_lines`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineChange {
+    /// The line was added or modified.
+    Added,
+    /// The line was removed or modified, so it is only in the parent.
+    Removed,
+    /// The line is in a changed file, but not changed itself.
+    Unchanged,
+}
+
+/// A single match of the pattern in a file of a commit.
+#[derive(Debug, Clone)]
+pub struct MatchEntry {
+    commit: Oid,
+    path: PathBuf,
+    start: usize,
+    end: usize,
+    line_number: usize,
+    end_line_number: usize,
+    column: usize,
+    line_start: usize,
+    line: String,
+    context_before: Vec<String>,
+    context_after: Vec<String>,
+    binary: bool,
+    hunk: Option<Vec<String>>,
+    change: Option<LineChange>,
+    more_matches: usize,
+    /// The working directory of the submodule that has the commit, or `None` for the repository
+    /// in the settings.
+    submodule: Option<PathBuf>,
+}
+
+impl MatchEntry {
+    /// The commit the match was found in.
+    pub fn commit(&self) -> Oid {
+        self.commit
+    }
+
+    /// The path of the file from the repository root.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Byte offset of the beginning of the match in the file, decoded in UTF-8.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Byte offset of the end of the match in the file, decoded in UTF-8.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// 1-based line number of the beginning of the match.
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    /// 1-based column of the beginning of the match in its line, counted in characters rather
+    /// than bytes.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// The 1-based line number of the last line of the match, which is after `line_number` only
+    /// for a match spanning lines with [`Settings::multiline`].
+    pub fn end_line_number(&self) -> usize {
+        self.end_line_number
+    }
+
+    /// Byte offset of the beginning of [`MatchEntry::line`] in the file.
+    pub fn line_start(&self) -> usize {
+        self.line_start
+    }
+
+    /// The line containing the match, without the newline.
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Lines before the matched line, as many as [`Settings::before_context`] if available.
+    pub fn context_before(&self) -> &[String] {
+        &self.context_before
+    }
+
+    /// Lines after the matched line, as many as [`Settings::after_context`] if available.
+    pub fn context_after(&self) -> &[String] {
+        &self.context_after
+    }
+
+    /// Whether the match is in a binary file with [`BinaryFiles::Binary`]. The match is only
+    /// reported to tell that the file matches, and the line is empty.
+    pub fn binary(&self) -> bool {
+        self.binary
+    }
+
+    /// With [`Settings::show_hunk`], the hunk of the diff from the first parent that has the
+    /// matched line, as the `@@` header followed by the lines prefixed with `+`, `-` or a space.
+    /// It is empty if the line is not changed from the parent.
+    pub fn hunk(&self) -> Option<&[String]> {
+        self.hunk.as_deref()
+    }
+
+    /// With [`Settings::diff_lines`], how the line was changed by the commit. Line numbers and
+    /// context of removed lines are of the file in the parent.
+    pub fn change(&self) -> Option<LineChange> {
+        self.change
+    }
+
+    /// The number of matches after this one in the same blob that were left out by
+    /// [`Settings::max_per_file`]. It is only set on the last match reported in the blob.
+    pub fn more_matches(&self) -> usize {
+        self.more_matches
+    }
+
+    /// The working directory of the submodule whose history has the commit, if the match is in
+    /// a submodule. Use [`GitGrep::repository`] to open the repository of the commit.
+    pub fn submodule(&self) -> Option<&Path> {
+        self.submodule.as_deref()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub pattern: Regex,
+    /// Only report matches in lines that also match all of these patterns.
+    pub and_patterns: Vec<Regex>,
+    /// Skip matches in lines that match any of these patterns.
+    pub not_patterns: Vec<Regex>,
+    pub repo: PathBuf,
+    /// Open `repo` as a bare repository, that is, the git directory itself.
+    pub bare: bool,
+    /// The branch to start walking from. Defaults to `HEAD`.
+    pub branch: Option<String>,
+    /// Start walking from all references, ignoring `branch`.
+    pub all: bool,
+    /// Start walking from all tags, ignoring `branch`.
+    pub tags: bool,
+    /// Start walking from the stashed states too, in addition to the other starting commits.
+    pub stash: bool,
+    /// Start walking from the commits in the reflogs of `HEAD` and all references too, which
+    /// can be unreachable from any of them.
+    pub reflog: bool,
+    /// A revision range like `A..B` to search commits reachable from `B` but not from `A`, like
+    /// `git log A..B`. Takes precedence over `branch` and `all`.
+    pub range: Option<String>,
+    /// A revision whose ancestors, including itself, are not searched, like `^A` in `git log`.
+    pub since_commit: Option<String>,
+    /// Only search the trees of the starting commits, without following their parents.
+    pub no_history: bool,
+    /// The number of generations of commits to walk from the starting ones, which are the first
+    /// generation. It is not the number of commits, which differs when there are merges.
+    pub depth: Option<usize>,
+    /// Follow only the first parent of merge commits, like `git log --first-parent`.
+    pub first_parent: bool,
+    /// Walk the history with the commit-graph file of the repository if it has one, which has
+    /// the parents and the generation numbers of commits. The walk stops without going down the
+    /// history of `range`, `since_commit` or `depth` once the rest cannot be searched, and the
+    /// commits are not read to walk. Without the file, the history is walked as usual. With
+    /// [`CommitOrder::Topological`], the commits come from the largest generation number, which
+    /// is a topological order too but can interleave branches differently from the usual walk.
+    pub sparse: bool,
+    /// The order to walk commits in, which is the order the commits are reported in.
+    pub sort: CommitOrder,
+    /// Search a file with the same path only once, in the first commit it was found.
+    pub once_file: bool,
+    /// Suppress a match if the same text at the same line of the same path was already reported
+    /// in another commit.
+    pub dedup: bool,
+    /// Only report matches whose line is not in the same path in the tree of `HEAD`, or of
+    /// `branch` if given, to find matching lines that were deleted.
+    pub deleted_only: bool,
+    /// Only search the files changed from the first parent in each commit, rather than the
+    /// whole tree. Files are not skipped by `once_file` or by blobs searched before.
+    pub diff_only: bool,
+    /// Detect renames in the diffs of `diff_only`, so that files renamed without changes are not
+    /// searched in the renaming commits. Renamed and changed files are searched like changed ones.
+    pub follow: bool,
+    /// Report each distinct matched line in each path only once, in the oldest commit it appears
+    /// in. Implies `once_file == false`.
+    pub first_introduced: bool,
+    /// Walk from the oldest commits, so that matches are reported in the chronological order.
+    /// With `max_count`, the oldest matches are reported.
+    pub reverse: bool,
+    /// Report lines that do not match the pattern instead, as matches spanning the whole line.
+    pub invert_match: bool,
+    /// Report only the first match in each line, so that a line with several matches is
+    /// reported once.
+    pub one_per_line: bool,
+    /// Report a match spanning lines with all of those lines as [`MatchEntry::line`], separated
+    /// by newlines. The pattern is expected to be built to match newlines, since it is searched in
+    /// the whole file either way.
+    pub multiline: bool,
+    pub before_context: usize,
+    pub after_context: usize,
+    /// The number of threads to walk commits with. `None` means the number of CPUs.
+    pub threads: Option<usize>,
+    /// The number of blobs to keep the line offsets of, so that they are not computed again if
+    /// the blob is searched again, which happens mostly with `diff_only`. 0 disables the cache.
+    pub blob_cache: usize,
+    /// Stop searching after this many matches.
+    pub max_count: Option<usize>,
+    /// Report at most this many matches in each blob. The number of the rest is given by
+    /// [`MatchEntry::more_matches`].
+    pub max_per_file: Option<usize>,
+    /// Only report matches starting at or after this 1-based line of each file.
+    pub min_line: Option<usize>,
+    /// Only report matches starting at or before this 1-based line of each file.
+    pub max_line: Option<usize>,
+    /// Set from another thread, like a Ctrl-C handler, to stop searching. The matches found so
+    /// far are still reported.
+    pub cancel: Arc<AtomicBool>,
+    /// Only search commits whose author name or email matches any of these patterns.
+    pub authors: Vec<Regex>,
+    /// Skip commits whose author name or email matches any of these patterns, even if they match
+    /// `authors`.
+    pub authors_not: Vec<Regex>,
+    /// Only search commits whose committer name or email matches any of these patterns. A commit
+    /// should match both this and `authors` if both are given.
+    pub committers: Vec<Regex>,
+    /// Only search commits committed at or after this time, in seconds since the Unix epoch.
+    pub since: Option<i64>,
+    /// Only search commits committed at or before this time, in seconds since the Unix epoch.
+    pub until: Option<i64>,
+    /// The encoding of files. `None` means UTF-8.
+    pub encoding: Option<&'static Encoding>,
+    /// Replace invalid sequences in files with U+FFFD rather than skipping those files.
+    pub lossy: bool,
+    /// Guess the encoding of files that are not valid UTF-8 when `encoding` is not given. Files
+    /// are skipped as undecodable if the guess is unreliable.
+    pub encoding_detect: bool,
+    pub binary_files: BinaryFiles,
+    /// Find the hunk of the diff from the first parent that has each matched line, which tells
+    /// whether the line was changed in the commit. It needs a diff for every file with matches.
+    pub show_hunk: bool,
+    /// With `diff_only`, tell whether each matched line was added in the commit, and report
+    /// matches in the lines removed by the commit too.
+    pub diff_lines: bool,
+    /// Skip blobs larger than this many bytes.
+    pub max_filesize: Option<usize>,
+    /// Log the counters of the walk at the debug level after each batch of commits. They are
+    /// only computed if this is set.
+    pub verbose: bool,
+    /// Show a progress bar of the commits searched on stderr.
+    pub progress: bool,
+    /// Extensions of files to search, in lower case without the dot. Files without an extension
+    /// are searched if their whole name is in this set. All files are searched if it is empty.
+    pub extensions: HashSet<OsString>,
+    /// Extensions of files to skip, in the same form as `extensions`. Takes precedence over it.
+    pub exclude_extensions: HashSet<OsString>,
+    /// Names of directories to skip, at any depth.
+    pub ignore_dirs: HashSet<OsString>,
+    /// If given, only search files whose path from the repository root matches any of these.
+    pub include: Option<GlobSet>,
+    /// Skip files whose path from the repository root matches any of these. Takes precedence
+    /// over `include`.
+    pub exclude: GlobSet,
+    /// Rules of ignore files given by the user, in the syntax of `.gitignore` and relative to the
+    /// repository root. They apply with or without `respect_gitignore`, and the `.gitignore`
+    /// files in the trees take precedence over them.
+    pub ignore_file: Gitignore,
+    /// A command to filter the contents of files through before searching them, which gets the
+    /// path of the file as the argument and the contents on stdin.
+    pub pre: Option<PathBuf>,
+    /// If given, only files whose path from the repository root matches any of these are
+    /// filtered by `pre`.
+    pub pre_glob: Option<GlobSet>,
+    /// If given, only search files whose path from the repository root matches this.
+    pub path_pattern: Option<Regex>,
+    /// Skip files whose path from the repository root matches this.
+    pub path_not_pattern: Option<Regex>,
+    /// Search commit messages too, in addition to the files.
+    pub search_messages: bool,
+    /// Match the pattern against the paths of files instead of their contents. A path matching
+    /// is reported once, as a match in the path taken as the line.
+    pub name_search: bool,
+    /// Report every file that passes the filters as a match without searching it, which is an
+    /// empty match at the start of the path taken as the line.
+    pub list_files: bool,
+    /// Skip files ignored by `.gitignore` files in the tree of each commit.
+    pub respect_gitignore: bool,
+    /// Search the history of checked out submodules too, from their `HEAD`.
+    pub recurse_submodules: bool,
+    /// Only search files under this directory, given relative to the root of the repository.
+    /// Paths of matches are still from the root.
+    pub subdir: Option<PathBuf>,
+    /// Prepended to the paths of matches, like the name of the repository when several are
+    /// searched. Filters on paths still see the paths from the root of the repository.
+    pub path_prefix: PathBuf,
+}
+
+impl Settings {
+    /// Creates settings to search `pattern` in the repository at `repo`, with the default filters.
+    pub fn new(pattern: Regex, repo: PathBuf) -> Self {
+        Self {
+            pattern,
+            and_patterns: vec![],
+            not_patterns: vec![],
+            repo,
+            bare: false,
+            branch: None,
+            all: false,
+            tags: false,
+            stash: false,
+            reflog: false,
+            range: None,
+            since_commit: None,
+            no_history: false,
+            depth: None,
+            first_parent: false,
+            sparse: false,
+            sort: CommitOrder::Topological,
+            once_file: true,
+            dedup: false,
+            diff_only: false,
+            first_introduced: false,
+            deleted_only: false,
+            follow: false,
+            reverse: false,
+            invert_match: false,
+            one_per_line: false,
+            multiline: false,
+            before_context: 0,
+            after_context: 0,
+            threads: None,
+            blob_cache: 0,
+            max_count: None,
+            max_per_file: None,
+            min_line: None,
+            max_line: None,
+            cancel: Arc::new(AtomicBool::new(false)),
+            authors: vec![],
+            authors_not: vec![],
+            committers: vec![],
+            since: None,
+            until: None,
+            encoding: None,
+            encoding_detect: false,
+            lossy: false,
+            binary_files: BinaryFiles::WithoutMatch,
+            show_hunk: false,
+            diff_lines: false,
+            max_filesize: None,
+            verbose: false,
+            progress: false,
+            extensions: DEFAULT_EXTENSIONS.iter().map(|ext| ext.into()).collect(),
+            exclude_extensions: HashSet::new(),
+            ignore_dirs: DEFAULT_IGNORE_DIRS.iter().map(|dir| dir.into()).collect(),
+            include: None,
+            exclude: GlobSet::empty(),
+            ignore_file: Gitignore::empty(),
+            pre: None,
+            pre_glob: None,
+            path_pattern: None,
+            path_not_pattern: None,
+            search_messages: false,
+            name_search: false,
+            list_files: false,
+            respect_gitignore: false,
+            recurse_submodules: false,
+            subdir: None,
+            path_prefix: PathBuf::new(),
+        }
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    fn accept_path(&self, path: &Path) -> bool {
+        if self.exclude.is_match(path)
+            || !self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.is_match(path))
+        {
+            return false;
+        }
+        let path = path.to_string_lossy();
+        self.path_pattern
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(&path))
+            && !self
+                .path_not_pattern
+                .as_ref()
+                .is_some_and(|pattern| pattern.is_match(&path))
+    }
+}
+
+/// Statistics of a search.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// The number of matches reported.
+    pub matches: usize,
+    /// The number of distinct lines with matches, which is less than `matches` if a line has
+    /// several of them.
+    pub matched_lines: usize,
+    /// The number of distinct paths that have matches.
+    pub files: usize,
+    /// The number of commits that have matches.
+    pub commits: usize,
+    /// The number of commits walked.
+    pub searched_commits: usize,
+    /// The number of trees walked, which are not walked with [`Settings::diff_only`].
+    pub searched_trees: usize,
+    /// The number of files searched.
+    pub searched_files: usize,
+    /// The number of files skipped because the same blob was already searched.
+    pub skipped_blobs: usize,
+    /// The number of matches suppressed by [`Settings::dedup`].
+    pub duplicates: usize,
+    /// Whether the search was stopped by [`Settings::cancel`] before the end.
+    pub cancelled: bool,
+}
+
+impl Stats {
+    /// Adds the statistics of another search, like the one in another repository.
+    pub fn add(&mut self, other: &Stats) {
+        self.matches += other.matches;
+        self.matched_lines += other.matched_lines;
+        self.files += other.files;
+        self.commits += other.commits;
+        self.searched_commits += other.searched_commits;
+        self.searched_trees += other.searched_trees;
+        self.searched_files += other.searched_files;
+        self.skipped_blobs += other.skipped_blobs;
+        self.duplicates += other.duplicates;
+        self.cancelled |= other.cancelled;
+    }
+}
+
+pub struct GitGrep {
+    settings: Settings,
+}
+
+impl GitGrep {
+    pub fn new(settings: Settings) -> Self {
+        Self { settings }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Walks the history and returns all the matches.
+    pub fn search(&self) -> Result<Vec<MatchEntry>> {
+        let mut matches = vec![];
+        self.search_with(|entry| {
+            matches.push(entry);
+            Ok(())
+        })?;
+        Ok(matches)
+    }
+
+    /// Walks the history and calls `callback` with each match. Matches are reported grouped by
+    /// commit, and by file within a commit. An error returned by `callback` stops the search and
+    /// is passed on to the caller.
+    pub fn search_with(&self, mut callback: impl FnMut(MatchEntry) -> Result<()>) -> Result<Stats> {
+        let prefix = &self.settings.path_prefix;
+        let mut callback = |mut entry: MatchEntry| {
+            if !prefix.as_os_str().is_empty() {
+                entry.path = prefix.join(&entry.path);
+            }
+            callback(entry)
+        };
+        let mut stats = process_files_git(&self.settings, &mut callback)?;
+        if self.settings.recurse_submodules {
+            search_submodules(&self.settings, Path::new(""), &mut callback, &mut stats)?;
+        }
+        Ok(stats)
+    }
+
+    /// Opens the repository that has the commit of a match, which is a submodule for matches
+    /// found with `recurse_submodules`.
+    pub fn repository(&self, entry: &MatchEntry) -> Result<Repository> {
+        Ok(match entry.submodule {
+            Some(ref submodule) => Repository::open(submodule)?,
+            None => open_repo(&self.settings)?,
+        })
+    }
+
+    /// Returns the short names of the references that point to each commit, like `main` or
+    /// `v1.0`, among the ones searched with `all` or `tags`. Tags are peeled to their commits.
+    pub fn ref_names(&self) -> Result<HashMap<Oid, Vec<String>>> {
+        let repo = open_repo(&self.settings)?;
+        let mut names: HashMap<Oid, Vec<String>> = HashMap::new();
+        for reference in repo.references()? {
+            let reference = reference?;
+            if self.settings.tags && !reference.is_tag() {
+                continue;
+            }
+            if let (Ok(commit), Some(name)) = (reference.peel_to_commit(), reference.shorthand()) {
+                names.entry(commit.id()).or_default().push(name.to_owned());
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Objects that have already been searched. It is shared among threads walking commits in
+/// parallel, so each of them can skip what the others have done.
+#[derive(Default)]
+struct Checked {
+    paths: Mutex<HashSet<PathBuf>>,
+    /// The blobs and the trees searched, with the position in the walk of the commit that has
+    /// searched them.
+    blobs: Mutex<HashMap<Oid, usize>>,
+    trees: Mutex<HashMap<Oid, usize>>,
+    /// The number of trees walked.
+    walked: AtomicUsize,
+    /// The number of files searched.
+    searched: AtomicUsize,
+    skipped_blobs: AtomicUsize,
+    /// The number of files skipped because they could not be decoded.
+    undecodable: AtomicUsize,
+    /// The number of files decoded in each encoding guessed by `encoding_detect`.
+    detected: Mutex<HashMap<&'static str, usize>>,
+    /// The number of files skipped because they are larger than `max_filesize`.
+    oversized: AtomicUsize,
+    /// The number of files skipped by their paths or sizes without reading their blobs.
+    skipped_before_read: AtomicUsize,
+    /// The number of matches found so far by all threads.
+    found: AtomicUsize,
+    line_indices: Mutex<LineIndexCache>,
+}
+
+impl Checked {
+    /// Marks a blob or a tree as searched by the commit at `position` of the walk, and tells if
+    /// it was not searched before. Commits in the same chunk are searched at once, so a later
+    /// commit of the walk can take an object first. With `first_introduced`, an earlier commit
+    /// takes it over and searches it again, so that it is not missing from the oldest commit.
+    fn claim(
+        objects: &Mutex<HashMap<Oid, usize>>,
+        id: Oid,
+        position: usize,
+        settings: &Settings,
+    ) -> bool {
+        match objects.lock().unwrap().entry(id) {
+            Entry::Vacant(entry) => {
+                entry.insert(position);
+                true
+            }
+            Entry::Occupied(mut entry) if settings.first_introduced && position < *entry.get() => {
+                entry.insert(position);
+                true
+            }
+            Entry::Occupied(_) => false,
+        }
+    }
+
+    /// Returns the line index of a blob from the cache, or computes it from the decoded content.
+    /// The lock is not held while computing, so that other threads are not blocked by a large
+    /// blob.
+    fn line_index(&self, blob: Oid, input: &str) -> Arc<LineIndex> {
+        if let Some(line_index) = self.line_indices.lock().unwrap().get(blob) {
+            return line_index;
+        }
+        let line_index = Arc::new(LineIndex::new(input));
+        self.line_indices
+            .lock()
+            .unwrap()
+            .insert(blob, line_index.clone());
+        line_index
+    }
+}
+
+/// Line indices of the most recently searched blobs.
+#[derive(Default)]
+struct LineIndexCache {
+    capacity: usize,
+    /// The least recently used entry first.
+    entries: VecDeque<(Oid, Arc<LineIndex>)>,
+    hits: usize,
+    misses: usize,
+}
+
+impl LineIndexCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    fn get(&mut self, blob: Oid) -> Option<Arc<LineIndex>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        match self.entries.iter().position(|(id, _)| *id == blob) {
+            Some(i) => {
+                self.hits += 1;
+                let entry = self.entries.remove(i)?;
+                let line_index = entry.1.clone();
+                self.entries.push_back(entry);
+                Some(line_index)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, blob: Oid, line_index: Arc<LineIndex>) {
+        if self.capacity == 0 || self.entries.iter().any(|(id, _)| *id == blob) {
+            return;
+        }
+        if self.capacity <= self.entries.len() {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((blob, line_index));
+    }
+}
+
+struct ProcessTree<'a> {
+    settings: &'a Settings,
+    repo: &'a Repository,
+    checked: &'a Checked,
+    matches: Vec<MatchEntry>,
+    /// Rules of `.gitignore` files in the directories from the root to the one being processed.
+    ignores: Vec<Gitignore>,
+    /// Blobs found by `process` to search later with `search_blobs`, in the order of the walk.
+    blobs: Vec<(Oid, PathBuf)>,
+    /// The position of the commit in the walk.
+    position: usize,
+}
+
+impl<'a> ProcessTree<'a> {
+    /// Tells if enough matches are found or the search is cancelled.
+    fn should_stop(&self) -> bool {
+        self.settings.cancelled()
+            || self
+                .settings
+                .max_count
+                .is_some_and(|max| max <= self.checked.found.load(Ordering::Relaxed))
+    }
+
+    fn process(&mut self, tree: &Tree, path: &Path) {
+        if !Checked::claim(&self.checked.trees, tree.id(), self.position, self.settings) {
+            return;
+        }
+        self.checked.walked.fetch_add(1, Ordering::Relaxed);
+        trace!("Walking tree {} at {:?}", tree.id(), path);
+
+        // A `.gitignore` applies to the directory it is in and below, relative to that directory,
+        // so we push its rules while walking the directory. They are part of the tree, so each
+        // commit is evaluated with the rules of its own time.
+        let gitignore = if self.settings.respect_gitignore {
+            self.load_gitignore(tree, path)
+        } else {
+            None
+        };
+        let pushed_gitignore = gitignore.is_some();
+        self.ignores.extend(gitignore);
+
+        for entry in tree {
+            if self.should_stop() {
+                break;
+            }
+            (|| {
+                let name = entry.name()?;
+                let entry_path = path.join(name);
+
+                let is_dir = entry.kind() == Some(ObjectType::Tree);
+                if is_dir && self.settings.ignore_dirs.contains(&OsString::from(name))
+                    || self.is_ignored(&entry_path, is_dir)
+                {
+                    return None;
+                }
+
+                // We want to match with absolute path from root, but it seems impossible with `tree.walk`.
+                let new_path = self
+                    .checked
+                    .paths
+                    .lock()
+                    .unwrap()
+                    .insert(entry_path.clone());
+                if self.settings.once_file && !self.settings.first_introduced && !new_path {
+                    return None;
+                }
+
+                // A submodule is a link to a commit in another repository, which is searched
+                // separately if `recurse_submodules` is set.
+                if entry.kind() == Some(ObjectType::Commit) {
+                    return None;
+                }
+                if entry.kind() == Some(ObjectType::Blob) {
+                    if !self.skip_before_read(entry.id(), &entry_path) {
+                        self.blobs.push((entry.id(), entry_path));
+                    }
+                    return None;
+                }
+
+                let obj = match entry.to_object(self.repo) {
+                    Ok(obj) => obj,
+                    Err(e) => {
+                        warn!("couldn't get_object: {:?}", e);
+                        return None;
+                    }
+                };
+                if obj.kind() == Some(ObjectType::Tree) {
+                    self.process(obj.as_tree()?, &entry_path);
+                }
+                Some(())
+            })();
+        }
+
+        if pushed_gitignore {
+            self.ignores.pop();
+        }
+    }
+
+    /// Searches the blobs collected by `process` in parallel, since a large tree has many of them
+    /// and they are independent. Like the workers of commits, each worker opens its own handle to
+    /// the repository. The matches are added in the order of the walk.
+    fn search_blobs(&mut self, commit: Oid) -> Result<()> {
+        let blobs = std::mem::take(&mut self.blobs);
+        let (settings, checked, position) = (self.settings, self.checked, self.position);
+        // Opening the repository costs more than searching a few blobs.
+        if blobs.len() < 2 * MIN_BLOBS_PER_TASK {
+            for (id, path) in &blobs {
+                if self.should_stop() {
+                    break;
+                }
+                let blob = match self.repo.find_blob(*id) {
+                    Ok(blob) => blob,
+                    Err(e) => {
+                        warn!("couldn't get_object: {:?}", e);
+                        continue;
+                    }
+                };
+                let matches = self.search_blob(&blob, commit, path).unwrap_or_default();
+                checked.found.fetch_add(matches.len(), Ordering::Relaxed);
+                self.matches.extend(matches);
+            }
+            return Ok(());
+        }
+        let results = blobs
+            .par_iter()
+            .with_min_len(MIN_BLOBS_PER_TASK)
+            .map_init(
+                || open_repo(settings),
+                |repo, (id, path)| -> Result<Vec<MatchEntry>> {
+                    let repo = repo.as_ref().map_err(|e| anyhow!("{}", e))?;
+                    let process_tree = ProcessTree {
+                        settings,
+                        repo,
+                        checked,
+                        matches: vec![],
+                        ignores: vec![],
+                        blobs: vec![],
+                        position,
+                    };
+                    if process_tree.should_stop() {
+                        return Ok(vec![]);
+                    }
+                    let blob = match repo.find_blob(*id) {
+                        Ok(blob) => blob,
+                        Err(e) => {
+                            warn!("couldn't get_object: {:?}", e);
+                            return Ok(vec![]);
+                        }
+                    };
+                    let matches = process_tree
+                        .search_blob(&blob, commit, path)
+                        .unwrap_or_default();
+                    checked.found.fetch_add(matches.len(), Ordering::Relaxed);
+                    Ok(matches)
+                },
+            )
+            .collect::<Result<Vec<_>>>()?;
+        self.matches.extend(results.into_iter().flatten());
+        Ok(())
+    }
+
+    /// Searches the directory at `subdir` in the tree, if the commit has it. The `.gitignore`
+    /// files in the directories above it apply, as if the whole tree were walked.
+    fn process_subdir(&mut self, tree: &Tree, subdir: &Path) {
+        let subtree = match tree
+            .get_path(subdir)
+            .and_then(|entry| entry.to_object(self.repo))
+            .ok()
+            .and_then(|obj| obj.into_tree().ok())
+        {
+            Some(subtree) => subtree,
+            None => return,
+        };
+        if self.settings.respect_gitignore {
+            let mut dir = PathBuf::new();
+            self.ignores.extend(self.load_gitignore(tree, &dir));
+            for name in subdir.parent().into_iter().flatten() {
+                dir.push(name);
+                let gitignore = tree
+                    .get_path(&dir)
+                    .and_then(|entry| entry.to_object(self.repo))
+                    .ok()
+                    .and_then(|obj| obj.into_tree().ok())
+                    .and_then(|dir_tree| self.load_gitignore(&dir_tree, &dir));
+                self.ignores.extend(gitignore);
+            }
+        }
+        self.process(&subtree, subdir);
+        self.ignores.clear();
+    }
+
+    /// Searches only the files changed from the first parent of the commit, or all files of a
+    /// root commit.
+    fn process_diff(&mut self, commit: &Commit) -> Result<()> {
+        let tree = commit.tree()?;
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let mut diff = self
+            .repo
+            .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+        if self.settings.follow {
+            diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+        }
+        for delta in diff.deltas() {
+            if self.should_stop() {
+                break;
+            }
+            // Deleted files have nothing to search, and submodules are not blobs.
+            let file = delta.new_file();
+            if delta.status() == Delta::Deleted || file.mode() == FileMode::Commit {
+                continue;
+            }
+            // The lines of a file moved as is were introduced before.
+            if delta.status() == Delta::Renamed && delta.old_file().id() == file.id() {
+                continue;
+            }
+            let path = match file.path() {
+                Some(path) => path,
+                None => continue,
+            };
+            if self
+                .settings
+                .subdir
+                .as_ref()
+                .is_some_and(|subdir| !path.starts_with(subdir))
+            {
+                continue;
+            }
+            let in_ignored_dir = path.parent().is_some_and(|dir| {
+                dir.iter()
+                    .any(|name| self.settings.ignore_dirs.contains(name))
+            });
+            if in_ignored_dir || self.is_ignored_in_tree(&tree, path) {
+                continue;
+            }
+            if self.skip_before_read(file.id(), path) {
+                continue;
+            }
+            let blob = match self.repo.find_blob(file.id()) {
+                Ok(blob) => blob,
+                Err(_) => continue,
+            };
+            if let Some(mut matches) = self.search_blob(&blob, commit.id(), path) {
+                if self.settings.diff_lines {
+                    self.mark_changes(
+                        &blob,
+                        delta.old_file().id(),
+                        commit.id(),
+                        path,
+                        &mut matches,
+                    );
+                }
+                self.checked
+                    .found
+                    .fetch_add(matches.len(), Ordering::Relaxed);
+                self.matches.extend(matches);
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the matches in a changed file by whether their lines were added, and adds the
+    /// matches in the lines removed from the old file. Removed lines are put before the lines
+    /// that follow them in the new file, like in a diff.
+    fn mark_changes(
+        &self,
+        blob: &Blob,
+        old_id: Oid,
+        commit: Oid,
+        path: &Path,
+        matches: &mut Vec<MatchEntry>,
+    ) {
+        let old_blob = if old_id.is_zero() {
+            None
+        } else {
+            self.repo.find_blob(old_id).ok()
+        };
+        let old = old_blob.as_ref().map_or(&[][..], |blob| blob.content());
+        let patch = match Patch::from_buffers(old, Some(path), blob.content(), Some(path), None) {
+            Ok(patch) => patch,
+            Err(_) => return,
+        };
+        let mut added = HashSet::new();
+        // Old line numbers of removed lines, and the new line numbers they are put before.
+        let mut removed = HashMap::new();
+        for i in 0..patch.num_hunks() {
+            let (hunk, line_count) = match patch.hunk(i) {
+                Ok(hunk) => hunk,
+                Err(_) => continue,
+            };
+            let mut next_new = hunk.new_start() as usize;
+            for j in 0..line_count {
+                let line = match patch.line_in_hunk(i, j) {
+                    Ok(line) => line,
+                    Err(_) => continue,
+                };
+                match (line.origin(), line.new_lineno(), line.old_lineno()) {
+                    ('+', Some(new), _) => {
+                        added.insert(new as usize);
+                        next_new = new as usize + 1;
+                    }
+                    (' ', Some(new), _) => next_new = new as usize + 1,
+                    ('-', _, Some(old)) => {
+                        removed.insert(old as usize, next_new);
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        // Removed lines come first at the same position.
+        let mut marked: Vec<_> = std::mem::take(matches)
+            .into_iter()
+            .map(|mut entry| {
+                entry.change = Some(if added.contains(&entry.line_number) {
+                    LineChange::Added
+                } else {
+                    LineChange::Unchanged
+                });
+                (entry.line_number, 1, entry)
+            })
+            .collect();
+        if !removed.is_empty() {
+            let old_matches = process_file(self.settings, commit, old, path, |input| {
+                Arc::new(LineIndex::new(input))
+            })
+            .unwrap_or_default();
+            for mut entry in old_matches {
+                if let Some(position) = removed.get(&entry.line_number) {
+                    entry.change = Some(LineChange::Removed);
+                    marked.push((*position, 0, entry));
+                }
+            }
+        }
+        marked.sort_by_key(|(position, kind, _)| (*position, *kind));
+        matches.extend(marked.into_iter().map(|(_, _, entry)| entry));
+    }
+
+    /// Tells if a file can be skipped by what is known without reading its blob, which are the
+    /// path, the size in the object header and whether the blob was searched before. Blobs of
+    /// files skipped here are not read at all.
+    fn skip_before_read(&self, id: Oid, path: &Path) -> bool {
+        let filtered = !self.settings.accept_path(path)
+            || !self.settings.extensions.is_empty()
+                && !has_extension(&self.settings.extensions, path)
+            || has_extension(&self.settings.exclude_extensions, path);
+        if filtered {
+            self.checked
+                .skipped_before_read
+                .fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        // Names are matched regardless of the contents.
+        if self.settings.name_search {
+            return false;
+        }
+
+        if let Some(max) = self.settings.max_filesize {
+            let size = self
+                .repo
+                .odb()
+                .and_then(|odb| odb.read_header(id))
+                .map_or(0, |(size, _)| size);
+            if max < size {
+                self.checked.oversized.fetch_add(1, Ordering::Relaxed);
+                self.checked
+                    .skipped_before_read
+                    .fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+        }
+
+        // A blob is searched in every commit that changes to it in diff mode, which is the point.
+        if !self.settings.diff_only
+            && !Checked::claim(&self.checked.blobs, id, self.position, self.settings)
+        {
+            self.checked.skipped_blobs.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    /// Searches a blob that passed [`ProcessTree::skip_before_read`], or returns `None` if it is
+    /// skipped by its contents.
+    fn search_blob(&self, blob: &Blob, commit: Oid, path: &Path) -> Option<Vec<MatchEntry>> {
+        // Names of any files can match, without looking into the contents.
+        if self.settings.name_search {
+            return Some(
+                name_match(commit, path, &self.settings.pattern)
+                    .into_iter()
+                    .collect(),
+            );
+        }
+
+        let filtered = self.pre_filter(blob, path);
+        let content = filtered.as_deref().unwrap_or(blob.content());
+        // The output of a filter depends on the path and on `pre_glob`, so the same blob can be
+        // searched as different contents, and their lines cannot be cached by the blob.
+        let line_index = |input: &str| {
+            if filtered.is_some() {
+                Arc::new(LineIndex::new(input))
+            } else {
+                self.checked.line_index(blob.id(), input)
+            }
+        };
+
+        // UTF-16 text is full of NULs, which git would take for binary.
+        let utf16 =
+            self.settings.encoding == Some(UTF_16LE) || self.settings.encoding == Some(UTF_16BE);
+        let is_binary = match filtered {
+            // Git looks for a NUL in the first 8000 bytes too.
+            Some(ref content) => content.iter().take(8000).any(|b| *b == 0),
+            None => blob.is_binary(),
+        };
+        let binary = is_binary && !utf16 && self.settings.binary_files != BinaryFiles::Text;
+        if binary && self.settings.binary_files == BinaryFiles::WithoutMatch {
+            return None;
+        }
+        if self.settings.list_files {
+            return Some(vec![path_entry(commit, path)]);
+        }
+
+        self.checked.searched.fetch_add(1, Ordering::Relaxed);
+        if binary {
+            // Binary files are rarely valid in any encoding, and only whether they match is
+            // reported anyway.
+            let content = String::from_utf8_lossy(content);
+            let mut matches =
+                process_file(self.settings, commit, content.as_bytes(), path, line_index)?;
+            matches.truncate(1);
+            for entry in &mut matches {
+                entry.binary = true;
+                entry.line.clear();
+                entry.context_before.clear();
+                entry.context_after.clear();
+            }
+            return Some(matches);
+        }
+
+        let detected = self.detect_encoding(content);
+        let content = detected.as_ref().map_or(content, |text| text.as_bytes());
+        // A decoder gives the same output for the same blob, so its lines can be cached still.
+        let mut matches = process_file(self.settings, commit, content, path, line_index);
+        match matches {
+            None => {
+                self.checked.undecodable.fetch_add(1, Ordering::Relaxed);
+            }
+            // The lines of filtered contents are not the lines of the diff.
+            Some(ref mut matches)
+                if self.settings.show_hunk && filtered.is_none() && !matches.is_empty() =>
+            {
+                self.attach_hunks(blob, commit, path, matches)
+            }
+            Some(_) => (),
+        }
+        matches
+    }
+
+    /// Decodes the contents in the encoding guessed by `encoding_detect` if they are not UTF-8.
+    /// The result is UTF-8 and searched as such.
+    fn detect_encoding(&self, content: &[u8]) -> Option<String> {
+        if !self.settings.encoding_detect
+            || self.settings.encoding.is_some()
+            || std::str::from_utf8(content).is_ok()
+        {
+            return None;
+        }
+        let mut detector = EncodingDetector::new();
+        detector.feed(content, true);
+        let (encoding, reliable) = detector.guess_assess(None, false);
+        if !reliable {
+            return None;
+        }
+        let (text, _, malformed) = encoding.decode(content);
+        if malformed && !self.settings.lossy {
+            return None;
+        }
+        *self
+            .checked
+            .detected
+            .lock()
+            .unwrap()
+            .entry(encoding.name())
+            .or_default() += 1;
+        Some(text.into_owned())
+    }
+
+    /// Runs the `pre` command on the contents of the blob if it applies to the path, and returns
+    /// its output. If the command fails, the raw contents are searched with a warning.
+    fn pre_filter(&self, blob: &Blob, path: &Path) -> Option<Vec<u8>> {
+        let command = self.settings.pre.as_ref()?;
+        if !self
+            .settings
+            .pre_glob
+            .as_ref()
+            .is_none_or(|glob| glob.is_match(path))
+        {
+            return None;
+        }
+        match run_pre(command, path, blob.content()) {
+            Ok(output) => Some(output),
+            Err(e) => {
+                warn!(
+                    "Pre-filter {:?} failed on {:?}, searching it as is: {}",
+                    command, path, e
+                );
+                None
+            }
+        }
+    }
+
+    /// Sets the hunk of the diff from the file in the first parent of the commit to each match.
+    /// A file that is new in the commit is diffed from an empty one.
+    fn attach_hunks(&self, blob: &Blob, commit: Oid, path: &Path, matches: &mut [MatchEntry]) {
+        let parent_blob = self
+            .repo
+            .find_commit(commit)
+            .and_then(|commit| commit.parent(0))
+            .and_then(|parent| parent.tree())
+            .and_then(|tree| tree.get_path(path))
+            .and_then(|entry| self.repo.find_blob(entry.id()))
+            .ok();
+        let old = parent_blob.as_ref().map_or(&[][..], |blob| blob.content());
+        let patch = match Patch::from_buffers(old, Some(path), blob.content(), Some(path), None) {
+            Ok(patch) => patch,
+            Err(_) => return,
+        };
+        for entry in matches {
+            entry.hunk = Some(hunk_of_line(&patch, entry.line_number).unwrap_or_default());
+        }
+    }
+
+    /// Tells if a file is ignored by the `.gitignore` files in the tree, which are looked up in
+    /// the directories to the file since the tree is not walked in diff mode.
+    fn is_ignored_in_tree(&mut self, tree: &Tree, path: &Path) -> bool {
+        if !self.settings.respect_gitignore {
+            return self
+                .settings
+                .ignore_file
+                .matched_path_or_any_parents(path, false)
+                .is_ignore();
+        }
+        let mut dir = PathBuf::new();
+        let mut ignored = false;
+        for (i, name) in path.iter().enumerate() {
+            let subtree = if i == 0 {
+                Some(tree.clone())
+            } else {
+                tree.get_path(&dir)
+                    .and_then(|entry| entry.to_object(self.repo))
+                    .ok()
+                    .and_then(|obj| obj.into_tree().ok())
+            };
+            self.ignores
+                .extend(subtree.and_then(|subtree| self.load_gitignore(&subtree, &dir)));
+            dir.push(name);
+            let is_dir = dir != path;
+            if self.is_ignored(&dir, is_dir) {
+                ignored = true;
+                break;
+            }
+        }
+        self.ignores.clear();
+        ignored
+    }
+
+    fn load_gitignore(&self, tree: &Tree, path: &Path) -> Option<Gitignore> {
+        let blob = tree
+            .get_name(".gitignore")?
+            .to_object(self.repo)
+            .ok()?
+            .peel_to_blob()
+            .ok()?;
+        let mut builder = GitignoreBuilder::new(path);
+        for line in String::from_utf8_lossy(blob.content()).lines() {
+            // Invalid lines are ignored like git does.
+            let _ = builder.add_line(None, line);
+        }
+        builder.build().ok()
+    }
+
+    /// Rules in deeper directories take precedence, and the first one matching either ignores
+    /// or whitelists the path.
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for gitignore in self.ignores.iter().rev() {
+            match gitignore.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => (),
+            }
+        }
+        self.settings.ignore_file.matched(path, is_dir).is_ignore()
+    }
+}
+
+/// Searches the history of every submodule of the repository at `settings.repo`, and their
+/// submodules in turn, from their own `HEAD`. Paths are reported from the root of the outermost
+/// repository, whose path to this one is `prefix`.
+fn search_submodules(
+    settings: &Settings,
+    prefix: &Path,
+    callback: &mut dyn FnMut(MatchEntry) -> Result<()>,
+    stats: &mut Stats,
+) -> Result<()> {
+    let repo = open_repo(settings)?;
+    // Submodules are checked out in the working tree, which a bare repository does not have.
+    if repo.is_bare() {
+        return Ok(());
+    }
+    for submodule in repo.submodules()? {
+        if settings
+            .subdir
+            .as_ref()
+            .is_some_and(|subdir| !submodule.path().starts_with(subdir))
+        {
+            continue;
+        }
+        let sub_repo = match submodule.open() {
+            Ok(sub_repo) => sub_repo,
+            Err(e) => {
+                debug!(
+                    "Skipping submodule {:?} which is not checked out: {}",
+                    prefix.join(submodule.path()),
+                    e
+                );
+                continue;
+            }
+        };
+        let max_count = settings
+            .max_count
+            .map(|max| max.saturating_sub(stats.matches));
+        if max_count == Some(0) || settings.cancelled() {
+            break;
+        }
+        let sub_settings = Settings {
+            repo: sub_repo
+                .workdir()
+                .unwrap_or_else(|| sub_repo.path())
+                .to_path_buf(),
+            // Branches, ranges and directories are names in the outer repository.
+            branch: None,
+            range: None,
+            subdir: None,
+            max_count,
+            ..settings.clone()
+        };
+        let sub_prefix = prefix.join(submodule.path());
+        let mut sub_callback = |mut entry: MatchEntry| {
+            entry.path = sub_prefix.join(&entry.path);
+            // The innermost submodule sees the match first.
+            entry
+                .submodule
+                .get_or_insert_with(|| sub_settings.repo.clone());
+            callback(entry)
+        };
+        let sub_stats = process_files_git(&sub_settings, &mut sub_callback)?;
+        stats.add(&sub_stats);
+        search_submodules(&sub_settings, &sub_prefix, &mut sub_callback, stats)?;
+    }
+    Ok(())
+}
+
+fn process_files_git(
+    settings: &Settings,
+    mut callback: impl FnMut(MatchEntry) -> Result<()>,
+) -> Result<Stats> {
+    let mut stats = Stats::default();
+    let mut files = HashSet::new();
+    let mut last_commit = None;
+    let mut last_line: Option<(Oid, PathBuf, usize)> = None;
+    // Matches arrive grouped by commit and then by file in the order of lines, so a commit or a
+    // line has matches if it differs from the last one.
+    let mut callback = |entry: MatchEntry| {
+        stats.matches += 1;
+        if last_commit != Some(entry.commit) {
+            stats.commits += 1;
+            last_commit = Some(entry.commit);
+        }
+        let new_line = last_line.as_ref().is_none_or(|(commit, path, line)| {
+            *commit != entry.commit || *path != entry.path || *line != entry.line_number
+        });
+        if new_line {
+            stats.matched_lines += 1;
+            last_line = Some((entry.commit, entry.path.clone(), entry.line_number));
+        }
+        files.insert(entry.path.clone());
+        callback(entry)
+    };
+
+    let tip_lines = if settings.deleted_only {
+        Some(tip_lines(settings)?)
+    } else {
+        None
+    };
+    let mut repo = open_repo(settings)?;
+    let mut commits = list_commits(&mut repo, settings)?;
+    if settings.reverse || settings.first_introduced {
+        // Walk from the oldest, so that the same blob or tree is searched in the oldest commit that
+        // has it and skipped in the later commits. Commits in the same chunk can still race for
+        // them, in which case the earlier commit of the walk searches them again, and the
+        // positions are compared when collecting the first matches.
+        commits.reverse();
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(settings.threads.unwrap_or(0))
+        .build()?;
+    let checked = Checked {
+        line_indices: Mutex::new(LineIndexCache::new(settings.blob_cache)),
+        ..Checked::default()
+    };
+    let mut match_count = 0;
+    let mut walked_commits = 0;
+    let mut first_matches: HashMap<(PathBuf, String), (usize, usize, MatchEntry)> = HashMap::new();
+    // Hashes of the matches reported by `dedup`, mapped to the first commit reporting them.
+    let mut emitted: HashMap<u64, Oid> = HashMap::new();
+    let mut duplicates = 0;
+
+    // The bar hides itself if stderr is not a terminal.
+    let progress = if settings.progress {
+        ProgressBar::new(commits.len() as u64).with_style(ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40} {pos}/{len} commits {msg}",
+        )?)
+    } else {
+        ProgressBar::hidden()
+    };
+
+    // Commits are processed in chunks so that every thread has something to work on, while the
+    // matches are still reported in the order of the walk.
+    let chunk_len = pool.current_num_threads() * 4;
+    for (chunk_index, chunk) in commits.chunks(chunk_len).enumerate() {
+        // Git objects cannot be sent across threads, so each worker opens its own handle to the
+        // repository and only object ids are passed around.
+        let results = pool.install(|| {
+            chunk
+                .par_iter()
+                .enumerate()
+                .map_init(
+                    || open_repo(settings),
+                    |repo, (i, id)| -> Result<(usize, Vec<MatchEntry>)> {
+                        let position = chunk_index * chunk_len + i;
+                        let repo = repo.as_ref().map_err(|e| anyhow!("{}", e))?;
+                        let commit = repo.find_commit(*id)?;
+                        let mut process_tree = ProcessTree {
+                            settings,
+                            repo,
+                            checked: &checked,
+                            matches: vec![],
+                            ignores: vec![],
+                            blobs: vec![],
+                            position,
+                        };
+                        if !settings.cancelled() && accept_commit(settings, &commit) {
+                            if settings.search_messages {
+                                let matches = process_file(
+                                    settings,
+                                    commit.id(),
+                                    commit.message_bytes(),
+                                    Path::new(COMMIT_MESSAGE_PATH),
+                                    |input| Arc::new(LineIndex::new(input)),
+                                )
+                                .unwrap_or_default();
+                                checked.found.fetch_add(matches.len(), Ordering::Relaxed);
+                                process_tree.matches.extend(matches);
+                            }
+                            if settings.diff_only {
+                                process_tree.process_diff(&commit)?;
+                            } else if let Ok(tree) = commit.tree() {
+                                match settings.subdir {
+                                    Some(ref subdir) => process_tree.process_subdir(&tree, subdir),
+                                    None => process_tree.process(&tree, &PathBuf::from("")),
+                                }
+                                process_tree.search_blobs(commit.id())?;
+                            }
+                        }
+                        Ok((position, process_tree.matches))
+                    },
+                )
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        // The bar is cleared while printing, so that it does not get mixed with the matches.
+        progress.suspend(|| -> Result<()> {
+            for (position, matches) in results {
+                let matches: Vec<_> = match tip_lines {
+                    Some(ref lines) => matches
+                        .into_iter()
+                        .filter(|entry| !lines.contains(&(entry.path.clone(), entry.line.clone())))
+                        .collect(),
+                    None => matches,
+                };
+                if settings.first_introduced {
+                    for entry in matches {
+                        let seq = first_matches.len();
+                        let key = (entry.path.clone(), entry.line.clone());
+                        match first_matches.get(&key) {
+                            Some((first, ..)) if *first <= position => (),
+                            _ => {
+                                first_matches.insert(key, (position, seq, entry));
+                            }
+                        }
+                    }
+                    match_count = first_matches.len();
+                    continue;
+                }
+                let matches: Vec<_> = if settings.dedup {
+                    matches
+                        .into_iter()
+                        .filter(|entry| {
+                            let first_commit =
+                                *emitted.entry(dedup_key(entry)).or_insert(entry.commit);
+                            if first_commit != entry.commit {
+                                duplicates += 1;
+                            }
+                            first_commit == entry.commit
+                        })
+                        .collect()
+                } else {
+                    matches
+                };
+                let remaining = settings
+                    .max_count
+                    .map_or(usize::MAX, |max| max.saturating_sub(match_count));
+                match_count += matches.len().min(remaining);
+                for entry in matches.into_iter().take(remaining) {
+                    callback(entry)?;
+                }
+            }
+            Ok(())
+        })?;
+        walked_commits += chunk.len();
+        progress.inc(chunk.len() as u64);
+        progress.set_message(format!("{} matches", match_count));
+
+        if settings.verbose {
+            let cache = if 0 < settings.blob_cache {
+                let cache = checked.line_indices.lock().unwrap();
+                format!(
+                    " {}/{} blob cache hits",
+                    cache.hits,
+                    cache.hits + cache.misses
+                )
+            } else {
+                String::new()
+            };
+            let detected = {
+                let detected = checked.detected.lock().unwrap();
+                let mut detected: Vec<_> = detected.iter().collect();
+                detected.sort();
+                detected
+                    .iter()
+                    .map(|(encoding, count)| format!(" {} {} files", count, encoding))
+                    .collect::<String>()
+            };
+            progress.suspend(|| {
+                debug!(
+                    "[{}/{}] {} Matches in {} files {} skipped blobs {} undecodable files {} oversized files {} skipped before read{}{}...",
+                    walked_commits,
+                    commits.len(),
+                    match_count,
+                    checked.searched.load(Ordering::Relaxed),
+                    checked.skipped_blobs.load(Ordering::Relaxed),
+                    checked.undecodable.load(Ordering::Relaxed),
+                    checked.oversized.load(Ordering::Relaxed),
+                    checked.skipped_before_read.load(Ordering::Relaxed),
+                    cache,
+                    detected,
+                )
+            });
+        }
+        if !settings.first_introduced && settings.max_count.is_some_and(|max| max <= match_count)
+            || settings.cancelled()
+        {
+            break;
+        }
+    }
+    progress.finish_and_clear();
+
+    if settings.first_introduced {
+        // Report in the order of the walk, which is from the oldest here, reversed back unless
+        // asked. The matches of a commit are kept together in the order they were found.
+        let positions: HashMap<Oid, usize> =
+            commits.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        let mut matches: Vec<_> = first_matches.into_values().collect();
+        matches.sort_by_key(|(_, seq, entry)| {
+            let position = positions[&entry.commit];
+            let position = if settings.reverse {
+                position
+            } else {
+                commits.len() - position
+            };
+            (position, *seq)
+        });
+        for (_, _, entry) in matches
+            .into_iter()
+            .take(settings.max_count.unwrap_or(usize::MAX))
+        {
+            callback(entry)?;
+        }
+    }
+
+    stats.files = files.len();
+    stats.searched_commits = walked_commits;
+    stats.searched_trees = checked.walked.load(Ordering::Relaxed);
+    stats.searched_files = checked.searched.load(Ordering::Relaxed);
+    stats.skipped_blobs = checked.skipped_blobs.load(Ordering::Relaxed);
+    stats.duplicates = duplicates;
+    stats.cancelled = settings.cancelled();
+    Ok(stats)
+}
+
+/// Hashes what identifies a match regardless of the commit, to tell duplicates with a small
+/// memory footprint.
+fn dedup_key(entry: &MatchEntry) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    entry.path.hash(&mut hasher);
+    entry.line_number.hash(&mut hasher);
+    let end = (entry.end - entry.line_start).min(entry.line.len());
+    let start = (entry.start - entry.line_start).min(end);
+    entry.line[start..end].hash(&mut hasher);
+    hasher.finish()
+}
+
+fn open_repo(settings: &Settings) -> std::result::Result<Repository, git2::Error> {
+    if settings.bare {
+        Repository::open_bare(&settings.repo)
+    } else {
+        Repository::open(&settings.repo)
+    }
+}
+
+/// Resolves a branch name like git does, falling back to the branch of the same name in any
+/// remote, so that a branch which is not checked out locally can be given without the remote.
+fn resolve_branch<'r>(repo: &'r Repository, branch: &str) -> Result<Reference<'r>> {
+    let err = match repo.resolve_reference_from_short_name(branch) {
+        Ok(reference) => return Ok(reference),
+        Err(err) => err,
+    };
+    for remote in repo.remotes()?.iter().flatten() {
+        if let Ok(reference) = repo.find_reference(&format!("refs/remotes/{}/{}", remote, branch)) {
+            return Ok(reference);
+        }
+    }
+    Err(err.into())
+}
+
+/// Lists the commits to search in the walking order. `repo` is mutable only to list stashes.
+fn list_commits(repo: &mut Repository, settings: &Settings) -> Result<Vec<Oid>> {
+    let extra_starts = extra_starts(repo, settings)?;
+    let mut revwalk = repo.revwalk()?;
+    // Generations are counted in the topological order, so the commits are sorted afterwards.
+    revwalk.set_sorting(match settings.sort {
+        _ if settings.depth.is_some() => Sort::TOPOLOGICAL | Sort::TIME,
+        CommitOrder::Topological => Sort::TOPOLOGICAL | Sort::TIME,
+        CommitOrder::Date => Sort::TIME,
+        CommitOrder::ReverseDate => Sort::TIME | Sort::REVERSE,
+    })?;
+
+    // Commits whose ancestors are not searched, like the start of a range.
+    let mut hidden = vec![];
+    let starts = if let Some(ref range) = settings.range {
+        let spec = repo.revparse(range)?;
+        let (from, to) = match (spec.from(), spec.to()) {
+            (Some(from), Some(to)) if spec.mode().contains(RevparseMode::RANGE) => (from, to),
+            _ => return Err(anyhow!("{:?} is not a range like A..B", range)),
+        };
+        hidden.push(from.peel_to_commit()?.id());
+        vec![to.peel_to_commit()?.id()]
+    } else if settings.all || settings.tags {
+        let mut starts = vec![];
+        for reference in repo.references()? {
+            let reference = reference?;
+            if settings.tags && !reference.is_tag() {
+                continue;
+            }
+            // Tags can point to trees or blobs, which have no history to walk.
+            if let Ok(commit) = reference.peel_to_commit() {
+                starts.push(commit.id());
+            }
+        }
+        starts
+    } else if let Some(ref branch) = settings.branch {
+        vec![resolve_branch(repo, branch)?.peel_to_commit()?.id()]
+    } else {
+        vec![repo.head()?.peel_to_commit()?.id()]
+    };
+    let mut starts = starts;
+    for start in extra_starts {
+        if !starts.contains(&start) {
+            starts.push(start);
+        }
+    }
+    if settings.no_history {
+        return Ok(starts);
+    }
+    if let Some(ref rev) = settings.since_commit {
+        let since = repo
+            .revparse_single(rev)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|e| anyhow!("Could not resolve the commit {:?}: {}", rev, e.message()))?;
+        hidden.push(since.id());
+    }
+
+    if settings.sparse {
+        match CommitGraph::open(repo) {
+            Ok(graph) => {
+                debug!("Walking with the commit-graph of {} commits", graph.len());
+                let mut commits = commit_graph::walk(
+                    repo,
+                    &graph,
+                    &starts,
+                    &hidden,
+                    settings.depth,
+                    settings.first_parent,
+                )?;
+                sort_by_time(repo, &mut commits, settings.sort)?;
+                return Ok(commits);
+            }
+            Err(e) => debug!("Walking without the commit-graph: {}", e),
+        }
+    }
+
+    for start in &starts {
+        revwalk.push(*start)?;
+    }
+    for id in &hidden {
+        revwalk.hide(*id)?;
+    }
+    if settings.first_parent {
+        revwalk.simplify_first_parent()?;
+    }
+
+    let depth = if let Some(depth) = settings.depth {
+        depth
+    } else {
+        return Ok(revwalk.collect::<std::result::Result<_, _>>()?);
+    };
+
+    // The generation of a commit is the shortest distance from the starting commits, which is
+    // settled by the time the commit is visited since all of its children come before it. Only
+    // commits within the depth are recorded, so we can stop once all of them are visited.
+    let mut generations: HashMap<Oid, usize> = starts.iter().map(|start| (*start, 0)).collect();
+    let mut pending = generations.len();
+    let mut commits = vec![];
+    for id in revwalk {
+        let id = id?;
+        let generation = if let Some(generation) = generations.get(&id) {
+            *generation
+        } else {
+            continue;
+        };
+        pending -= 1;
+        if generation < depth {
+            commits.push(id);
+        }
+        if generation + 1 < depth {
+            let parents = if settings.first_parent { 1 } else { usize::MAX };
+            for parent in repo.find_commit(id)?.parent_ids().take(parents) {
+                let entry = generations.entry(parent).or_insert_with(|| {
+                    pending += 1;
+                    generation + 1
+                });
+                *entry = (*entry).min(generation + 1);
+            }
+        }
+        if pending == 0 {
+            break;
+        }
+    }
+    sort_by_time(repo, &mut commits, settings.sort)?;
+    Ok(commits)
+}
+
+/// Sorts commits listed in a topological order by their time, unless the order is topological.
+fn sort_by_time(repo: &Repository, commits: &mut [Oid], sort: CommitOrder) -> Result<()> {
+    if sort != CommitOrder::Topological {
+        let mut times = HashMap::new();
+        for id in commits.iter() {
+            times.insert(*id, repo.find_commit(*id)?.time().seconds());
+        }
+        match sort {
+            CommitOrder::Date => commits.sort_by_key(|id| std::cmp::Reverse(times[id])),
+            _ => commits.sort_by_key(|id| times[id]),
+        }
+    }
+    Ok(())
+}
+
+/// Pipes `input` through `command` with the path as the argument, and returns what it writes.
+fn run_pre(command: &Path, path: &Path, input: &[u8]) -> Result<Vec<u8>> {
+    let mut child = Command::new(command)
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // Writing from another thread keeps the command from blocking on a full stdout pipe, which
+    // we read only after writing all the input otherwise. The command may exit without reading
+    // all of it, so the error of writing is not an error of the command.
+    let output = std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let _ = stdin.write_all(input);
+        });
+        child.wait_with_output()
+    })?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Returns the lines of the hunk in a patch that has the 1-based line of the new file, if any.
+fn hunk_of_line(patch: &Patch, line_number: usize) -> Option<Vec<String>> {
+    for i in 0..patch.num_hunks() {
+        let (hunk, line_count) = patch.hunk(i).ok()?;
+        let start = hunk.new_start() as usize;
+        if line_number < start || start + hunk.new_lines() as usize <= line_number {
+            continue;
+        }
+        let mut lines = vec![String::from_utf8_lossy(hunk.header()).trim_end().to_owned()];
+        for j in 0..line_count {
+            let line = patch.line_in_hunk(i, j).ok()?;
+            // Other origins are markers like the one for no newline at the end of file.
+            if let origin @ ('+' | '-' | ' ') = line.origin() {
+                let content = String::from_utf8_lossy(line.content());
+                lines.push(format!(
+                    "{}{}",
+                    origin,
+                    content.trim_end_matches(&['\r', '\n'][..])
+                ));
+            }
+        }
+        return Some(lines);
+    }
+    None
+}
+
+/// Returns whether the extension of a file is in `extensions`, or its whole name if it has no
+/// extension.
+fn has_extension(extensions: &HashSet<OsString>, path: &Path) -> bool {
+    match path.extension() {
+        Some(ext) => extensions.contains(&ext.to_ascii_lowercase()),
+        None => path
+            .file_name()
+            .is_some_and(|name| extensions.contains(name)),
+    }
+}
+
+/// Returns the commits in the stash and the reflogs if asked. A repository without them just
+/// has none.
+fn extra_starts(repo: &mut Repository, settings: &Settings) -> Result<Vec<Oid>> {
+    let mut starts = vec![];
+    if settings.stash {
+        repo.stash_foreach(|_, _, id| {
+            starts.push(*id);
+            true
+        })?;
+    }
+    if settings.reflog {
+        let mut names = vec!["HEAD".to_owned()];
+        for reference in repo.references()? {
+            names.extend(reference?.name().map(|name| name.to_owned()));
+        }
+        for name in names {
+            for entry in repo.reflog(&name)?.iter() {
+                let id = entry.id_new();
+                // Deleting a branch is recorded with the zero id, and the objects of old entries
+                // may have been pruned.
+                if !id.is_zero() && repo.find_commit(id).is_ok() {
+                    starts.push(id);
+                }
+            }
+        }
+    }
+    Ok(starts)
+}
+
+/// Returns the paths and the lines of the matches in the tree of `HEAD` or `branch`, which are
+/// the ones still there for `deleted_only`. The filters of commits do not apply to the tip, and
+/// its whole tree is searched even with `diff_only`, since a line is there whether or not the
+/// tip commit changed it.
+fn tip_lines(settings: &Settings) -> Result<HashSet<(PathBuf, String)>> {
+    let settings = Settings {
+        all: false,
+        tags: false,
+        stash: false,
+        reflog: false,
+        range: None,
+        since_commit: None,
+        no_history: true,
+        diff_only: false,
+        diff_lines: false,
+        follow: false,
+        search_messages: false,
+        dedup: false,
+        first_introduced: false,
+        deleted_only: false,
+        max_count: None,
+        max_per_file: None,
+        min_line: None,
+        max_line: None,
+        authors: vec![],
+        authors_not: vec![],
+        committers: vec![],
+        since: None,
+        until: None,
+        progress: false,
+        verbose: false,
+        ..settings.clone()
+    };
+    let mut lines = HashSet::new();
+    process_files_git(&settings, |entry| {
+        lines.insert((entry.path, entry.line));
+        Ok(())
+    })?;
+    Ok(lines)
+}
+
+/// Returns whether the files in `commit` should be searched according to the commit filters.
+///
+/// The patterns of each filter are alternatives, and the filters must all pass: a commit is
+/// searched if its author matches any of `authors` and none of `authors_not`, and its committer
+/// matches any of `committers`. An empty filter passes every commit.
+fn accept_commit(settings: &Settings, commit: &Commit) -> bool {
+    if !settings.authors.is_empty() || !settings.authors_not.is_empty() {
+        let author = commit.author();
+        if !settings.authors.is_empty() && !signature_matches(&author, &settings.authors) {
+            return false;
+        }
+        if signature_matches(&author, &settings.authors_not) {
+            return false;
+        }
+    }
+    if !settings.committers.is_empty()
+        && !signature_matches(&commit.committer(), &settings.committers)
+    {
+        return false;
+    }
+    let time = commit.time().seconds();
+    if settings.since.is_some_and(|since| time < since)
+        || settings.until.is_some_and(|until| until < time)
+    {
+        return false;
+    }
+    true
+}
+
+/// Returns whether the name or the email matches any of the patterns.
+fn signature_matches(signature: &Signature, patterns: &[Regex]) -> bool {
+    let matches = |field: Option<&str>| {
+        field.is_some_and(|field| patterns.iter().any(|pattern| pattern.is_match(field)))
+    };
+    matches(signature.name()) || matches(signature.email())
+}
+
+/// Returns the first match of `pattern` in the path, as if the path were the only line of a file.
+fn name_match(commit: Oid, path: &Path, pattern: &Regex) -> Option<MatchEntry> {
+    let mut entry = path_entry(commit, path);
+    let found = pattern.find(&entry.line)?;
+    entry.start = found.start();
+    entry.end = found.end();
+    entry.column = entry.line[..found.start()].chars().count() + 1;
+    Some(entry)
+}
+
+/// Returns an empty match at the start of the path, as if the path were the only line of a file.
+fn path_entry(commit: Oid, path: &Path) -> MatchEntry {
+    MatchEntry {
+        commit,
+        path: path.to_path_buf(),
+        start: 0,
+        end: 0,
+        line_number: 1,
+        end_line_number: 1,
+        column: 1,
+        line_start: 0,
+        line: path.to_string_lossy().into_owned(),
+        context_before: vec![],
+        context_after: vec![],
+        binary: false,
+        hunk: None,
+        change: None,
+        more_matches: 0,
+        submodule: None,
+    }
+}
+
+/// Returns matches in a file, or `None` if it cannot be decoded.
+fn process_file(
+    settings: &Settings,
+    commit: Oid,
+    input: &[u8],
+    filepath: &Path,
+    line_index: impl FnOnce(&str) -> Arc<LineIndex>,
+) -> Option<Vec<MatchEntry>> {
+    let input_str = &*decode(settings, input)?;
+
+    let line_index = line_index(input_str);
+    let lines = |range: Range<usize>| -> Vec<String> {
+        range
+            .map(|i| input_str[line_index.line_range(i)].to_owned())
+            .collect()
+    };
+
+    let make_entry = |start: usize, end: usize, line: usize| {
+        // A newline ending the match does not take it to the next line.
+        let end_line = if settings.multiline && start < end {
+            line_index.line_of(end - 1).min(line_index.line_count() - 1)
+        } else {
+            line
+        };
+        let before_start = line.saturating_sub(settings.before_context);
+        let after_end = (end_line + 1 + settings.after_context).min(line_index.line_count());
+        let line_range = line_index.line_range(line);
+        let line_end = line_index.line_range(end_line).end;
+        MatchEntry {
+            commit,
+            path: filepath.to_path_buf(),
+            start,
+            end,
+            line_number: line + 1,
+            end_line_number: end_line + 1,
+            column: input_str[line_range.start..start].chars().count() + 1,
+            line_start: line_range.start,
+            line: input_str[line_range.start..line_end].to_owned(),
+            context_before: lines(before_start..line),
+            context_after: lines((end_line + 1).min(after_end)..after_end),
+            binary: false,
+            hunk: None,
+            change: None,
+            more_matches: 0,
+            submodule: None,
+        }
+    };
+
+    // The 0-based range of lines to report matches in.
+    let first_line = settings.min_line.map_or(0, |min| min.saturating_sub(1));
+    let last_line = settings.max_line.unwrap_or(usize::MAX);
+
+    // Whether a line with a match of the pattern is reported by the other patterns.
+    let line_accepted = |line: usize| {
+        let text = &input_str[line_index.line_range(line)];
+        settings
+            .and_patterns
+            .iter()
+            .all(|pattern| pattern.is_match(text))
+            && !settings
+                .not_patterns
+                .iter()
+                .any(|pattern| pattern.is_match(text))
+    };
+
+    // The byte ranges of matches with their 0-based lines.
+    let mut prev_line = None;
+    let mut found: Box<dyn Iterator<Item = (usize, usize, usize)>> = if settings.invert_match {
+        // Inverted matches are whole lines, so we walk line by line rather than through matches.
+        Box::new(
+            (first_line..line_index.line_count().min(last_line)).filter_map(|line| {
+                let range = line_index.line_range(line);
+                if settings.pattern.is_match(&input_str[range.clone()]) && line_accepted(line) {
+                    None
+                } else {
+                    Some((range.start, range.end, line))
+                }
+            }),
+        )
+    } else {
+        Box::new(
+            settings
+                .pattern
+                .find_iter(input_str)
+                .map(|found| {
+                    (
+                        found.start(),
+                        found.end(),
+                        line_index.line_of(found.start()),
+                    )
+                })
+                .skip_while(|(_, _, line)| *line < first_line)
+                .take_while(|(_, _, line)| *line < last_line)
+                .filter(|(_, _, line)| {
+                    // An empty match at the very end of a file ending with a newline is not in a
+                    // line.
+                    *line < line_index.line_count()
+                        && line_accepted(*line)
+                        && !(settings.one_per_line && prev_line.replace(*line) == Some(*line))
+                }),
+        )
+    };
+
+    let mut matches: Vec<_> = found
+        .by_ref()
+        .take(settings.max_per_file.unwrap_or(usize::MAX))
+        .map(|(start, end, line)| make_entry(start, end, line))
+        .collect();
+    // The rest are only counted, without the cost of making their entries.
+    if let Some(last) = matches.last_mut() {
+        last.more_matches = found.count();
+    }
+    Some(matches)
+}
+
+/// Decodes a file with the encoding in the settings, or UTF-8 by default.
+fn decode<'a>(settings: &Settings, input: &'a [u8]) -> Option<Cow<'a, str>> {
+    match settings.encoding {
+        Some(encoding) if settings.lossy => Some(encoding.decode(input).0),
+        Some(encoding) => encoding.decode_without_bom_handling_and_without_replacement(input),
+        None if settings.lossy => Some(String::from_utf8_lossy(input)),
+        None => std::str::from_utf8(input).ok().map(Cow::Borrowed),
+    }
+}
+
+/// Byte offsets of the beginnings of lines in a file, so that the line of a match can be found
+/// by binary search rather than scanning the file from the start for each match.
+///
+/// Assumes newlines would not be part of multibyte character, which is true for utf8 that is the
+/// only supported encoding in Rust anyway.
+struct LineIndex {
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl LineIndex {
+    fn new(input: &str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(
+                input
+                    .bytes()
+                    .enumerate()
+                    .filter(|(_, c)| *c == b'\n')
+                    .map(|(i, _)| i + 1),
+            )
+            .collect();
+        Self {
+            starts,
+            len: input.len(),
+        }
+    }
+
+    /// The number of lines. The first and the last line of the file need not to have a newline
+    /// around them, but the empty string after the last newline is not a line.
+    fn line_count(&self) -> usize {
+        if self.starts.last() == Some(&self.len) && 1 < self.starts.len() {
+            self.starts.len() - 1
+        } else {
+            self.starts.len()
+        }
+    }
+
+    /// Returns the 0-based index of the line containing the byte at `offset`.
+    fn line_of(&self, offset: usize) -> usize {
+        self.starts.partition_point(|start| *start <= offset) - 1
+    }
+
+    /// Returns the byte range of a line, excluding the newline.
+    fn line_range(&self, line: usize) -> Range<usize> {
+        let start = self.starts[line];
+        let end = self.starts.get(line + 1).map_or(self.len, |next| next - 1);
+        start..end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Signature;
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// Creates a repository with a commit of the files in its working directory.
+    fn repo_with(files: &[(&str, &str)]) -> (TempDir, Repository) {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        commit(&repo, files);
+        (dir, repo)
+    }
+
+    /// Commits the files on top of `HEAD`, if any.
+    fn commit(repo: &Repository, files: &[(&str, &str)]) -> Oid {
+        let mut index = repo.index().unwrap();
+        for (path, content) in files {
+            let full = repo.workdir().unwrap().join(path);
+            fs::create_dir_all(full.parent().unwrap()).unwrap();
+            fs::write(full, content).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("test", "test@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "commit",
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    fn search(settings: Settings) -> Vec<MatchEntry> {
+        GitGrep::new(settings).search().unwrap()
+    }
+
+    fn settings(pattern: &str, repo: &Repository) -> Settings {
+        Settings {
+            extensions: HashSet::new(),
+            ..Settings::new(
+                Regex::new(pattern).unwrap(),
+                repo.workdir().unwrap().to_path_buf(),
+            )
+        }
+    }
+
+    /// The 1-based line numbers, the columns and the lines of the matches in a file.
+    fn lines_of(pattern: &str, input: &str) -> Vec<(usize, usize, String)> {
+        let settings = Settings {
+            once_file: false,
+            ..Settings::new(Regex::new(pattern).unwrap(), PathBuf::new())
+        };
+        let matches = process_file(
+            &settings,
+            Oid::zero(),
+            input.as_bytes(),
+            Path::new("a.rs"),
+            |input| Arc::new(LineIndex::new(input)),
+        );
+        matches
+            .unwrap_or_default()
+            .into_iter()
+            .map(|entry| (entry.line_number, entry.column, entry.line))
+            .collect()
+    }
+
+    #[test]
+    fn matches_on_the_first_and_the_last_lines() {
+        assert_eq!(
+            lines_of("foo", "foo a\nb\nc foo"),
+            [(1, 1, "foo a".to_owned()), (3, 3, "c foo".to_owned())]
+        );
+        assert_eq!(
+            lines_of("foo", "foo a\nb\nc foo\n"),
+            [(1, 1, "foo a".to_owned()), (3, 3, "c foo".to_owned())]
+        );
+    }
+
+    #[test]
+    fn matches_in_a_single_line_file() {
+        assert_eq!(
+            lines_of("o", "foo"),
+            [(1, 2, "foo".to_owned()), (1, 3, "foo".to_owned())]
+        );
+    }
+
+    #[test]
+    fn matches_in_crlf_lines() {
+        // Only the newline ends a line, so the carriage return stays in it as in git grep.
+        assert_eq!(
+            lines_of("b", "a\r\nb\r\nc b"),
+            [(2, 1, "b\r".to_owned()), (3, 3, "c b".to_owned())]
+        );
+    }
+
+    #[test]
+    fn line_index_agrees_with_counting_newlines() {
+        // Lines of varying lengths, some of them empty, with a match on every third line.
+        let input: String = (0..20_000)
+            .map(|i| match i % 3 {
+                0 => format!("{} foo {}\n", "x".repeat(i % 17), i),
+                1 => "\n".to_owned(),
+                _ => format!("bar {}\n", i),
+            })
+            .collect();
+        let found = lines_of("foo", &input);
+        assert_eq!(found.len(), 6667);
+        for (line_number, column, line) in found {
+            let i = line_number - 1;
+            assert_eq!(line, format!("{} foo {}", "x".repeat(i % 17), i));
+            assert_eq!(column, i % 17 + 2);
+        }
+
+        let index = LineIndex::new(&input);
+        assert_eq!(index.line_count(), 20_000);
+        for offset in (0..input.len()).step_by(97) {
+            assert_eq!(index.line_of(offset), input[..offset].matches('\n').count());
+        }
+    }
+
+    /// The commits, the paths, the line numbers and the lines of the matches.
+    fn found(matches: &[MatchEntry]) -> Vec<(Oid, PathBuf, usize, String)> {
+        matches
+            .iter()
+            .map(|entry| {
+                (
+                    entry.commit(),
+                    entry.path().to_path_buf(),
+                    entry.line_number(),
+                    entry.line().to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    /// A history where the same blobs are searched in several commits, and again in the diffs.
+    fn history() -> (TempDir, Repository) {
+        let (dir, repo) = repo_with(&[("a.rs", "foo\nbar foo\n"), ("b.rs", "foo b\n")]);
+        commit(&repo, &[("c.rs", "c foo\n")]);
+        commit(&repo, &[("a.rs", "foo\n")]);
+        commit(&repo, &[("b.rs", "b\nfoo b\n")]);
+        (dir, repo)
+    }
+
+    #[test]
+    fn bare_repository_is_searched_without_a_working_directory() {
+        let (dir, repo) = history();
+        let bare_dir = TempDir::new().unwrap();
+        let bare = git2::build::RepoBuilder::new()
+            .bare(true)
+            .clone(dir.path().to_str().unwrap(), bare_dir.path())
+            .unwrap();
+        assert!(bare.workdir().is_none());
+
+        let expected = found(&search(Settings {
+            once_file: false,
+            ..settings("foo", &repo)
+        }));
+        let matches = search(Settings {
+            once_file: false,
+            bare: true,
+            ..Settings {
+                extensions: HashSet::new(),
+                ..Settings::new(Regex::new("foo").unwrap(), bare_dir.path().to_path_buf())
+            }
+        });
+        assert_eq!(found(&matches), expected);
+        assert!(!expected.is_empty());
+    }
+
+    #[test]
+    fn branch_falls_back_to_a_remote_branch() {
+        let (dir, repo) = history();
+        let first = repo
+            .revparse_single("HEAD~3")
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        repo.branch("old", &first, false).unwrap();
+        let clone_dir = TempDir::new().unwrap();
+        git2::build::RepoBuilder::new()
+            .clone(dir.path().to_str().unwrap(), clone_dir.path())
+            .unwrap();
+
+        let matches = search(Settings {
+            branch: Some("old".to_owned()),
+            extensions: HashSet::new(),
+            ..Settings::new(Regex::new("foo").unwrap(), clone_dir.path().to_path_buf())
+        });
+        assert!(!matches.is_empty());
+        assert!(matches.iter().all(|entry| entry.commit() == first.id()));
+    }
+
+    #[test]
+    fn cached_lines_give_the_same_matches() {
+        let (_dir, repo) = history();
+        for diff_only in [false, true] {
+            let search_with = |blob_cache| {
+                found(&search(Settings {
+                    once_file: false,
+                    diff_only,
+                    blob_cache,
+                    ..settings("foo", &repo)
+                }))
+            };
+            let uncached = search_with(0);
+            assert!(!uncached.is_empty());
+            assert_eq!(search_with(1), uncached);
+            assert_eq!(search_with(100), uncached);
+        }
+    }
+
+    #[test]
+    fn first_introduced_with_threads_credits_the_oldest_commit() {
+        let (_dir, repo) = repo_with(&[("n.rs", "0\n")]);
+        commit(&repo, &[("n.rs", "1\n")]);
+        let added = commit(&repo, &[("a.rs", "foo\nbar foo\n"), ("b/c.rs", "c foo\n")]);
+        // The file stays the same in the later commits, which are in the same chunk.
+        for i in 2..40 {
+            commit(&repo, &[("n.rs", &format!("{}\n", i))]);
+        }
+        for _ in 0..8 {
+            let matches = search(Settings {
+                first_introduced: true,
+                once_file: false,
+                threads: Some(8),
+                ..settings("foo", &repo)
+            });
+            assert_eq!(matches.len(), 3);
+            assert!(matches.iter().all(|entry| entry.commit() == added));
+        }
+    }
+
+    #[test]
+    fn deleted_only_with_diff_only_searches_the_whole_tip() {
+        let (_dir, repo) = repo_with(&[("a.rs", "keep foo\n"), ("b.rs", "old foo\n")]);
+        commit(&repo, &[("b.rs", "new\n")]);
+        let matches = search(Settings {
+            deleted_only: true,
+            diff_only: true,
+            once_file: false,
+            ..settings("foo", &repo)
+        });
+        let lines: Vec<_> = matches.iter().map(|entry| entry.line()).collect();
+        assert_eq!(lines, ["old foo"]);
+    }
+
+    #[test]
+    fn submodule_matches_resolve_commits_in_the_submodule() {
+        let (sub_dir, sub_repo) = repo_with(&[("s.rs", "sub foo\n")]);
+        let sub_commit = sub_repo.head().unwrap().target().unwrap();
+        let (dir, repo) = repo_with(&[("t.rs", "top foo\n")]);
+        let status = std::process::Command::new("git")
+            .args(["-c", "protocol.file.allow=always", "submodule", "add", "-q"])
+            .arg(sub_dir.path())
+            .arg("sub")
+            .current_dir(dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let top_commit = commit(&repo, &[]);
+
+        let grep = GitGrep::new(Settings {
+            recurse_submodules: true,
+            ..settings("foo", &repo)
+        });
+        let matches = grep.search().unwrap();
+        let found: Vec<_> = matches
+            .iter()
+            .map(|entry| {
+                let repo = grep.repository(entry).unwrap();
+                let commit = repo.find_commit(entry.commit()).unwrap();
+                (entry.path().to_str().unwrap(), commit.id())
+            })
+            .collect();
+        assert_eq!(found, [("t.rs", top_commit), ("sub/s.rs", sub_commit)]);
+        assert_eq!(matches[0].submodule(), None);
+        assert!(matches[1].submodule().unwrap().ends_with("sub"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pre_filtered_blob_does_not_share_cached_lines() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let content = "fn foo() {}\n".repeat(5);
+        let (dir, repo) = repo_with(&[("a.rs", &content), ("b.rs", &content)]);
+        // The filter makes the contents shorter than the blob, so lines cached from one would be
+        // out of bounds in the other.
+        let pre = dir.path().join("pre.sh");
+        fs::write(&pre, "#!/bin/sh\ncat > /dev/null\necho 'fn foo'\n").unwrap();
+        fs::set_permissions(&pre, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut glob = globset::GlobSetBuilder::new();
+        glob.add(globset::Glob::new("a.rs").unwrap());
+        let matches = search(Settings {
+            diff_only: true,
+            blob_cache: 10,
+            pre: Some(pre),
+            pre_glob: Some(glob.build().unwrap()),
+            ..settings("fn foo", &repo)
+        });
+        let lines: Vec<_> = matches
+            .iter()
+            .map(|entry| {
+                (
+                    entry.path().to_str().unwrap(),
+                    entry.line_number(),
+                    entry.line(),
+                )
+            })
+            .collect();
+        let mut expected = vec![("a.rs", 1, "fn foo")];
+        expected.extend((1..=5).map(|line| ("b.rs", line, "fn foo() {}")));
+        assert_eq!(lines, expected);
+    }
+}
@@ -1,384 +1,2924 @@
+mod config;
+mod format;
+mod tui;
+
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, TimeZone};
 use colored::*;
+use config::RcFile;
 use dunce::canonicalize;
-use git2::{Commit, ObjectType, Oid, Repository, Tree};
-use regex::Regex;
+use encoding_rs::Encoding;
+use format::{Template, SHORT_COMMIT_LEN};
+use git2::{Oid, Repository};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::{info, log_enabled, warn, Level, LevelFilter};
+use regex::{Captures, Regex, RegexBuilder};
+use rust_gitgrep::{
+    BinaryFiles, CommitOrder, GitGrep, LineChange, MatchEntry, Settings, Stats, DEFAULT_EXTENSIONS,
+    DEFAULT_IGNORE_DIRS, FILE_TYPES,
+};
 use std::{
-    collections::{HashMap, HashSet},
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap, HashSet},
     convert::{TryFrom, TryInto},
     env,
     ffi::OsString,
-    path::{Path, PathBuf},
+    fs::File,
+    io::{self, BufWriter, IsTerminal, Write},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
 };
 use structopt::StructOpt;
 
+/// The length of commit ids with `--abbrev` without a number.
+const DEFAULT_ABBREV: usize = 8;
+
+/// The template of `--vimgrep`, where the short commit tells which version of the path matched.
+const VIMGREP_FORMAT: &str = "{path}@{short_commit}:{line}:{col}:{text}";
+
 #[derive(Debug, StructOpt)]
 struct Opt {
     #[structopt(
-        help = "The pattern to search for. Shall be a regular expression passed to regex crate."
+        required_unless_one = &["regexp", "file", "type-list", "list-files"],
+        help = "The pattern to search for. Shall be a regular expression passed to regex crate. If -e or -f is given, this is taken as the repo instead."
+    )]
+    pattern: Option<String>,
+    #[structopt(
+        help = "Root repo to grep. If it is a directory in the working tree of a repo, only the directory is searched in each commit. Without it, the repo is found from the current directory up like git"
     )]
-    pattern: String,
-    #[structopt(help = "Root repo to grep")]
     repo: Option<PathBuf>,
-    #[structopt(short, long, help = "Branch name")]
+    #[structopt(
+        long,
+        help = "Read the paths of repos to search from stdin, one per line, instead of the repo argument. Paths of matches are prefixed by the repo path as given, and the rc file of the current directory is used"
+    )]
+    repo_from_stdin: bool,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Only search files under the directory, relative to the repo argument or to the current directory without it, in each commit"
+    )]
+    subdir: Option<PathBuf>,
+    #[structopt(
+        short = "e",
+        long,
+        number_of_values = 1,
+        help = "A pattern to search for. Can be given multiple times to search for any of them"
+    )]
+    regexp: Vec<String>,
+    #[structopt(
+        short = "f",
+        long,
+        number_of_values = 1,
+        parse(from_os_str),
+        help = "Read patterns to search for from the file, one per line. Empty lines and lines starting with # are skipped"
+    )]
+    file: Vec<PathBuf>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        value_name = "REGEX",
+        help = "Only report matches in lines that also match the regular expression. Can be given multiple times to require all of them. Flags like --ignore-case and --fixed-strings apply to it too"
+    )]
+    and: Vec<String>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        value_name = "REGEX",
+        help = "Skip matches in lines that match the regular expression. Can be given multiple times to skip lines matching any of them"
+    )]
+    not: Vec<String>,
+    #[structopt(
+        long,
+        help = "Open the repo as a bare repository, that is, the git directory itself. Bare repositories are usually detected without this"
+    )]
+    bare: bool,
+    #[structopt(
+        short,
+        long,
+        help = "Branch name. It can be a remote-tracking branch like origin/main, and the branch of the same name in a remote is used if there is no local one"
+    )]
     branch: Option<String>,
     #[structopt(
         short,
         long,
-        help = "Search from all branches. Ignores -b option if given"
+        visible_alias = "all-refs",
+        help = "Search from all branches and tags. Ignores -b option if given"
     )]
     all: bool,
-    #[structopt(short, long, help = "Depth to search into git commit history")]
+    #[structopt(long, help = "Search from all tags. Ignores -b option if given")]
+    tags: bool,
+    #[structopt(
+        long,
+        help = "Search the stashed states too, in addition to the branch or the references given otherwise"
+    )]
+    stash: bool,
+    #[structopt(
+        long,
+        help = "Search the commits in the reflogs of HEAD and all references too, including ones no longer reachable from any branch"
+    )]
+    reflog: bool,
+    #[structopt(
+        long,
+        help = "Search only commits in a range A..B, which are reachable from B but not from A like git log A..B. If A is not an ancestor of B, their common ancestors are excluded too. Ignores -b and -a options if given"
+    )]
+    range: Option<String>,
+    #[structopt(
+        long,
+        value_name = "REV",
+        help = "Search only commits that are not the given one or its ancestors, like everything after a release tag. Unlike --range, it applies to the starting commits given by other options"
+    )]
+    since_commit: Option<String>,
+    #[structopt(
+        long,
+        alias = "working-tree",
+        help = "Search only the tree of the tip commit, without following the history"
+    )]
+    no_history: bool,
+    #[structopt(
+        long,
+        help = "Search only the trees of the tips of all branches and tags, without following the history, like -a with --no-history. Commits are printed with the names of the references pointing to them"
+    )]
+    tips_only: bool,
+    #[structopt(
+        short,
+        long,
+        help = "Depth to search into git commit history, in generations from the starting commits rather than in commits. 1 searches only the starting commits, 2 also their parents, and so on. Both parents of a merge are in the same generation, so more than N commits can be searched"
+    )]
     depth: Option<usize>,
     #[structopt(
-        short = "o",
+        long,
+        help = "Follow only the first parent of merge commits, like git log --first-parent, to search the mainline without the commits merged into it"
+    )]
+    first_parent: bool,
+    #[structopt(
+        long,
+        help = "Walk the history with the commit-graph file of the repository (written by git commit-graph write or git gc) if it has one, so that --range, --since-commit and --depth stop without going through the older history. The usual walk is used without the file. Whether it was used is shown with --verbose. With --sort topo, commits are ordered by their generation numbers in the file, so branches can be interleaved differently from the usual walk"
+    )]
+    sparse: bool,
+    #[structopt(
+        long,
+        possible_values = &["topo", "date", "reverse-date"],
+        parse(try_from_str = parse_commit_order),
+        help = "The order to search and print commits in. topo (the default) puts children before their parents and newer commits first otherwise, date sorts by the commit time from the newest, and reverse-date from the oldest"
+    )]
+    sort: Option<CommitOrder>,
+    #[structopt(
+        short = "O",
         long,
         help = "Turn off showing matches to a file only once; the default behavior is that if the same file with the same name has different versions that matches, they will not be printed."
     )]
     no_once_file: bool,
+    #[structopt(
+        long,
+        help = "Report each distinct matched line of a file only once, in the oldest commit it appears in. Implies --no-once-file"
+    )]
+    first_introduced: bool,
+    #[structopt(
+        long,
+        help = "Search the oldest commits first, printing matches in chronological order. With -m, the oldest matches are printed"
+    )]
+    reverse: bool,
+    #[structopt(
+        long,
+        help = "Do not print a match if the same text at the same line of the same path was already printed for another commit"
+    )]
+    dedup: bool,
+    #[structopt(
+        long,
+        help = "Only print matches whose line is no longer in the same path at the tip of the branch, to find matching lines that were deleted. Every version of a path is searched, as with -O"
+    )]
+    deleted_only: bool,
+    #[structopt(
+        long,
+        help = "Only search the files changed in each commit from its first parent, to find the commits that touched the matching lines"
+    )]
+    diff_only: bool,
+    #[structopt(
+        long,
+        help = "With --diff-only, detect renamed files so that a file renamed without changes is not searched again in the commit renaming it, as its lines came from earlier commits"
+    )]
+    follow: bool,
     #[structopt(
         short = "c",
         long,
-        help = "Disable color coding for the output, default is to use colors in terminal"
+        help = "Disable color coding for the output. Same as --color never"
     )]
     no_color_code: bool,
+    #[structopt(
+        long,
+        possible_values = &["auto", "always", "never"],
+        help = "When to use color codes in the output. auto (the default) uses colors only if the output is a terminal"
+    )]
+    color: Option<ColorChoice>,
     #[structopt(
         short = "g",
         long,
         help = "Disable output grouping. Better for machine inputs"
     )]
     no_output_grouping: bool,
-    #[structopt(short, long, help = "Verbose flag")]
+    #[structopt(
+        long,
+        possible_values = &["commit", "file", "none"],
+        help = "Group the matches under each commit (the default), under each path across commits with the commit on each line, or not at all like --no-output-grouping. Grouping by file prints nothing until the search is done"
+    )]
+    group_by: Option<GroupBy>,
+    #[structopt(
+        long,
+        possible_values = &["relative", "absolute"],
+        help = "Print the paths of matches relative to the repository root (the default), or absolute by joining them to the path of the working directory. Absolute paths are only joined, so they need not exist on disk, for example for files removed in later commits"
+    )]
+    path_style: Option<PathStyle>,
+    #[structopt(
+        long,
+        help = "Print the path of a file once as a heading above its matches in each commit, rather than on every line. Ignored with -g and -Z"
+    )]
+    heading: bool,
+    #[structopt(
+        long,
+        overrides_with = "heading",
+        help = "Print the path on every line, which is the default. Overrides --heading"
+    )]
+    no_heading: bool,
+    #[structopt(
+        long,
+        conflicts_with_all = &["json", "format", "count", "count-matches", "files-with-matches", "files-without-match", "count-commits", "commits-only", "summary-per-commit", "output"],
+        help = "Browse the matches in the terminal after the search, from the list of commits with matches to the matched lines of each. Quit with q"
+    )]
+    interactive: bool,
+    #[structopt(
+        long,
+        help = "Emit one JSON object per match (NDJSON) instead of human readable lines. Implies no color codes."
+    )]
+    json: bool,
+    #[structopt(
+        long,
+        conflicts_with_all = &["json", "format", "vimgrep", "interactive", "count-by", "files-with-matches"],
+        help = "Collect all matches and emit them at the end as one JSON object, with the repos and the patterns searched and the array of matches"
+    )]
+    json_array: bool,
+    #[structopt(
+        long,
+        help = "Print each match by the template instead of the default layout, replacing {commit}, {short_commit}, {path}, {line}, {col} and {text}. Write {{ and }} for literal braces. Context lines are not printed"
+    )]
+    format: Option<String>,
+    #[structopt(
+        long,
+        conflicts_with_all = &["json", "format"],
+        help = "Print each match as path@short_commit:line:column:text for the quickfix list of editors, like --vimgrep of ripgrep. No grouping, color or context lines"
+    )]
+    vimgrep: bool,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Write the results to the given file instead of stdout. Disables color codes unless --color always is given"
+    )]
+    output: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Print the number of matching lines per commit and in total instead of the matched lines. A line with several matches is counted once"
+    )]
+    count: bool,
+    #[structopt(
+        long,
+        help = "Print the number of matches per commit and in total like --count, but counting every match in a line"
+    )]
+    count_matches: bool,
+    #[structopt(
+        long,
+        possible_values = &["commit", "file"],
+        help = "Break the counts of --count or --count-matches down per commit, or per file in each commit"
+    )]
+    count_by: Option<CountBy>,
+    #[structopt(
+        long,
+        conflicts_with_all = &["count", "count-matches"],
+        help = "Print in how many commits each file has matches instead of the matched lines, the most first. Implies --no-once-file. Since each version of a file is searched once, this is the number of its versions with matches, or of the commits changing it with matches with --diff-only"
+    )]
+    count_commits: bool,
+    #[structopt(
+        long,
+        value_name = "N",
+        requires = "count-commits",
+        help = "With --count-commits, print only the N files matching in the most commits"
+    )]
+    top: Option<usize>,
+    #[structopt(
+        long,
+        help = "Print each distinct matched line only once, however many commits and files have it. With --count, print how many times each of them occurred instead, like uniq -c"
+    )]
+    unique_lines: bool,
+    #[structopt(
+        long,
+        requires = "unique-lines",
+        help = "With --unique-lines, tell lines apart per file rather than across all files"
+    )]
+    unique_per_file: bool,
+    #[structopt(
+        short = "l",
+        long,
+        help = "Print only the paths of files with matches, each path once across all the commits. Since a path is searched only in the first commit it is found unless -O is given, this lists files whose latest version matches by default, and files that ever matched with -O"
+    )]
+    files_with_matches: bool,
+    #[structopt(
+        short = "L",
+        long,
+        conflicts_with_all = &["files-with-matches", "list-files", "commits-only"],
+        help = "Print only the paths of files without matches among the files that pass the filters, each path once across all the commits like -l. A path is listed if none of its versions searched matches, which is its latest version by default and every version with -O"
+    )]
+    files_without_match: bool,
+    #[structopt(
+        long,
+        conflicts_with_all = &["json", "json-array", "format", "vimgrep", "interactive", "count-by", "files-with-matches"],
+        help = "Print only the commits with matches, one line each with the short commit id (7 digits, or as many as --abbrev gives), the date and the subject, from the newest or from the oldest with --reverse. Since a path is searched only in the first commit it is found unless -O is given, give -O to list every commit whose files match"
+    )]
+    commits_only: bool,
+    #[structopt(
+        long,
+        conflicts_with_all = &["json", "json-array", "format", "vimgrep", "interactive", "count-by", "files-with-matches", "commits-only"],
+        help = "Print only the commits with matches like --commits-only, one line each with the short commit id (7 digits, or as many as --abbrev gives), the subject and the numbers of files and matches in the commit. Give -O to count the files that are unchanged from the commits searched before"
+    )]
+    summary_per_commit: bool,
+    #[structopt(
+        long,
+        requires = "files-with-matches",
+        help = "With --files-with-matches, list a path once per commit with matches, prefixed by the commit"
+    )]
+    by_commit: bool,
+    #[structopt(
+        long,
+        require_equals = true,
+        value_name = "N",
+        help = "Abbreviate commit ids to N hex digits given like --abbrev=N, or 8 digits with only --abbrev. Full 40 digits are printed by default, and 7 digits for {short_commit} of --format and --vimgrep, --commits-only and --summary-per-commit"
+    )]
+    abbrev: Option<Option<usize>>,
+    #[structopt(
+        short = "Z",
+        long,
+        alias = "print0-filenames",
+        help = "Separate the commit, path, line number and line by NUL instead of the usual decorations. With -l, -L or --list-files, terminate each path by NUL instead of a newline, for xargs -0. Implies no color codes"
+    )]
+    null: bool,
+    #[structopt(
+        long,
+        value_name = "SEP",
+        conflicts_with = "null",
+        help = "Separate the commit, path, line number and line by SEP instead of the usual decorations, like -Z does with NUL"
+    )]
+    field_separator: Option<String>,
+    #[structopt(
+        long,
+        value_name = "SEP",
+        help = "The line printed between blocks of context lines that are not contiguous. The default is --"
+    )]
+    context_separator: Option<String>,
+    #[structopt(
+        long,
+        help = "Print the 1-based column of matches in characters, after the line number"
+    )]
+    column: bool,
+    #[structopt(
+        short = "M",
+        long,
+        value_name = "NUM",
+        help = "Cut printed lines longer than NUM characters, marking the cut ends with an ellipsis. The part around the match is kept, and columns are still of the whole line"
+    )]
+    max_columns: Option<usize>,
+    #[structopt(
+        short = "o",
+        long,
+        help = "Print only the matched parts of lines, each match on its own line. Context lines are not printed. -o used to be the short option of --no-once-file, which is now -O"
+    )]
+    only_matching: bool,
+    #[structopt(
+        long,
+        conflicts_with_all = &["only-matching", "replace", "multiline", "format", "vimgrep", "json"],
+        help = "Print each matched line once as a whole with all the matches in it highlighted, rather than once for each match. The column is of the first match"
+    )]
+    highlight_only: bool,
+    #[structopt(
+        short = "r",
+        long,
+        help = "Print matched lines with every match replaced by the template, which can refer to capture groups like $1 or ${name}. This is only a preview; nothing is ever written to the repo"
+    )]
+    replace: Option<String>,
+    #[structopt(
+        short = "v",
+        long,
+        help = "Print lines that do not match the pattern. --verbose has no short option accordingly"
+    )]
+    invert_match: bool,
+    #[structopt(
+        long,
+        alias = "only-first-match-per-line",
+        help = "Report only the first match in each line, so that a line is printed and counted once even if the pattern matches it several times"
+    )]
+    one_per_line: bool,
+    #[structopt(
+        short = "A",
+        long,
+        help = "Print NUM lines of trailing context after matching lines"
+    )]
+    after_context: Option<usize>,
+    #[structopt(
+        short = "B",
+        long,
+        help = "Print NUM lines of leading context before matching lines"
+    )]
+    before_context: Option<usize>,
+    #[structopt(
+        short = "C",
+        long,
+        help = "Print NUM lines of context around matching lines. -A and -B take precedence if given"
+    )]
+    context: Option<usize>,
+    #[structopt(
+        long,
+        help = "Number of threads to walk commits with. Defaults to the number of CPUs. Commits are printed in the order they are walked, but with more than one thread, which commit a file or a blob that appears in several commits is attributed to may vary between runs"
+    )]
+    threads: Option<usize>,
+    #[structopt(
+        long,
+        value_name = "N",
+        help = "Keep the line offsets of the last N searched blobs, so that a blob searched again, as in every commit changing to it with --diff-only, is not scanned for newlines again. The hit rate is shown with --verbose"
+    )]
+    blob_cache: Option<usize>,
+    #[structopt(short = "m", long, help = "Stop searching after NUM matches")]
+    max_count: Option<usize>,
+    #[structopt(
+        long,
+        value_name = "N",
+        help = "Print at most N matches in each file of a commit, followed by a note of how many more it has. Unlike --max-count, the search goes on to other files"
+    )]
+    max_per_file: Option<usize>,
+    #[structopt(
+        long,
+        value_name = "N",
+        help = "Only report matches starting at or after line N of each file"
+    )]
+    min_line: Option<usize>,
+    #[structopt(
+        long,
+        value_name = "N",
+        help = "Only report matches starting at or before line N of each file"
+    )]
+    max_line: Option<usize>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Only search commits whose author name or email matches the regular expression. Can be given multiple times to match any of them"
+    )]
+    author: Vec<String>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Skip commits whose author name or email matches the regular expression, even if they match --author. Can be given multiple times to skip any of them"
+    )]
+    author_not: Vec<String>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Only search commits whose committer name or email matches the regular expression. Can be given multiple times to match any of them. With --author, commits should match both"
+    )]
+    committer: Vec<String>,
+    #[structopt(
+        long,
+        help = "Only search commits committed at or after the date, given in RFC3339 or YYYY-MM-DD"
+    )]
+    since: Option<String>,
+    #[structopt(
+        long,
+        help = "Only search commits committed at or before the date, given in RFC3339 or YYYY-MM-DD. A date without time includes the whole day"
+    )]
+    until: Option<String>,
+    #[structopt(
+        long,
+        help = "Decode files with the encoding, given as a WHATWG label like shift_jis, latin1 or utf-16le, instead of UTF-8"
+    )]
+    encoding: Option<String>,
+    #[structopt(
+        long,
+        help = "Replace invalid byte sequences with U+FFFD instead of skipping files that cannot be decoded"
+    )]
+    lossy: bool,
+    #[structopt(
+        long,
+        conflicts_with = "encoding",
+        help = "Guess the encoding of files that are not valid UTF-8 and search them decoded. Files are still skipped if the guess is unreliable. The guessed encodings are reported with --verbose"
+    )]
+    encoding_detect: bool,
+    #[structopt(
+        long,
+        possible_values = &["without-match", "text", "binary"],
+        parse(try_from_str = parse_binary_files),
+        help = "How to search files that look binary. without-match (the default) skips them, text searches them like text files, and binary only tells whether each of them matches"
+    )]
+    binary_files: Option<BinaryFiles>,
+    #[structopt(
+        long,
+        alias = "before-context-commit",
+        help = "Print the hunk of the diff from the first parent that has each matched line below it, to tell whether the line was changed in the commit or carried over. Slow since every file with matches is diffed"
+    )]
+    show_hunk: bool,
+    #[structopt(
+        long,
+        requires = "diff-only",
+        help = "With --diff-only, mark each matched line with + if the commit added it, - if the commit removed it or a space otherwise, like a diff. Lines removed by the commit are searched too"
+    )]
+    diff_lines: bool,
+    #[structopt(
+        long,
+        help = "Search binary files like text files. Same as --binary-files text"
+    )]
+    text: bool,
+    #[structopt(
+        long,
+        help = "Skip files larger than the size in bytes, which can have a suffix K, M or G for KiB, MiB or GiB like 1M"
+    )]
+    max_filesize: Option<String>,
+    #[structopt(
+        long,
+        help = "Print the counters of the walk after each batch of commits and other details on stderr. Same as --log-level debug"
+    )]
     verbose: bool,
-    #[structopt(short, long, help = "Add an entry to list of extensions to search")]
+    #[structopt(
+        short = "q",
+        long,
+        conflicts_with = "verbose",
+        help = "Do not print the banner and other diagnostics on stderr, except errors. With --exit-code, print nothing at all and stop at the first match, only telling whether anything matches"
+    )]
+    quiet: bool,
+    #[structopt(
+        long,
+        help = "Show a progress bar of the commits searched on stderr. It is only drawn if stderr is a terminal"
+    )]
+    progress: bool,
+    #[structopt(
+        long,
+        possible_values = &["off", "error", "warn", "info", "debug", "trace"],
+        help = "How much to print on stderr besides the matches. warn prints only warnings like blobs that could not be read, info (the default) adds the banner, debug adds the details of --verbose, and trace every tree walked. Defaults to error with --quiet"
+    )]
+    log_level: Option<LevelFilter>,
+    #[structopt(
+        long,
+        help = "Print a summary of the matches and the searched commits and files on stderr at the end"
+    )]
+    stat: bool,
+    #[structopt(
+        long,
+        require_equals = true,
+        value_name = "PATH",
+        help = "Write a summary of the search as a JSON object at the end, to stderr or to the file given like --stats-json=PATH"
+    )]
+    stats_json: Option<Option<PathBuf>>,
+    #[structopt(
+        long,
+        help = "Exit with status 0 if anything matched, 1 if nothing matched and 2 on errors, like grep"
+    )]
+    exit_code: bool,
+    #[structopt(
+        long,
+        help = "Add an entry to list of extensions to search, with or without the dot. A file without an extension is searched if its whole name is given, like Makefile"
+    )]
     extensions: Vec<String>,
     #[structopt(
-        short,
+        long,
+        help = "Do not search the default list of extensions, so that only ones given by --extensions are searched, or all text files if none are given"
+    )]
+    no_default_extensions: bool,
+    #[structopt(
+        short = "t",
+        long = "type",
+        number_of_values = 1,
+        help = "Search only files of the type, like rust or web, instead of the default extensions. Can be given multiple times and combined with --extensions. See --type-list for the types"
+    )]
+    types: Vec<String>,
+    #[structopt(
+        short = "T",
+        long = "type-not",
+        number_of_values = 1,
+        help = "Skip files of the type, even if their extension is given otherwise"
+    )]
+    types_not: Vec<String>,
+    #[structopt(
+        long,
+        help = "Print the known file types and their extensions, and exit"
+    )]
+    type_list: bool,
+    #[structopt(
+        long,
+        conflicts_with_all = &["regexp", "file"],
+        help = "Print the paths of the files that would be searched by the filters on paths, sizes and binary files, each path once, without searching them. The pattern is not given, so the first positional argument is the repo"
+    )]
+    list_files: bool,
+    #[structopt(
+        short = "I",
         long,
         help = "Add an entry to list of directory names to ignore"
     )]
     ignore_dirs: Vec<String>,
+    #[structopt(
+        long,
+        help = "Do not ignore the default list of directories like .git, target and node_modules, so that only ones given by --ignore-dirs are ignored"
+    )]
+    no_ignore_defaults: bool,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Only search files whose path from the repository root matches the glob, e.g. 'src/**/*.rs'. Can be given multiple times to match any of them"
+    )]
+    include: Vec<String>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Skip files whose path from the repository root matches the glob, e.g. '**/generated/**'. Takes precedence over --include"
+    )]
+    exclude: Vec<String>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        parse(from_os_str),
+        help = "Skip files matching the rules in the file, written like .gitignore and relative to the repository root. Can be given multiple times to use all of them"
+    )]
+    ignore_file: Vec<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "COMMAND",
+        parse(from_os_str),
+        help = "Search the output of the command for each file instead of the contents, like --pre of ripgrep. The command gets the path as the argument and the contents on stdin. If it fails, the contents are searched as is"
+    )]
+    pre: Option<PathBuf>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        requires = "pre",
+        help = "Only filter files whose path from the repository root matches the glob with --pre, e.g. '**/*.gz'. Can be given multiple times to filter files matching any of them"
+    )]
+    pre_glob: Vec<String>,
+    #[structopt(
+        long,
+        help = "Only search files whose path from the repository root matches the regular expression, e.g. 'test.*\\.rs$'"
+    )]
+    path_pattern: Option<String>,
+    #[structopt(
+        long,
+        help = "Skip files whose path from the repository root matches the regular expression"
+    )]
+    path_not_pattern: Option<String>,
+    #[structopt(
+        long,
+        help = "Search commit messages too. Matches in them are reported with the path <commit message>"
+    )]
+    search_messages: bool,
+    #[structopt(
+        long,
+        help = "Match the pattern against the paths of files rather than their contents, printing each matching path. The extension and path filters still apply, so give --no-default-extensions to match any file"
+    )]
+    name_search: bool,
+    #[structopt(
+        long,
+        help = "Skip files ignored by .gitignore files found in the tree of each commit"
+    )]
+    respect_gitignore: bool,
+    #[structopt(
+        long,
+        help = "Search the history of checked out submodules too, from their HEAD. Paths are shown from the root of the repo"
+    )]
+    recurse_submodules: bool,
+    #[structopt(
+        short = "i",
+        long,
+        help = "Case insensitive search. Applies to the whole pattern, but inline flags like (?-i) in the pattern still take precedence for their group."
+    )]
+    ignore_case: bool,
+    #[structopt(
+        short = "S",
+        long,
+        alias = "ignore-case-smart",
+        help = "Case insensitive search unless a pattern has an uppercase letter. Letters in escapes like \\W or \\p{Lu}, group names and flags are not counted"
+    )]
+    smart_case: bool,
+    #[structopt(
+        long,
+        value_name = "SIZE",
+        help = "The largest compiled size of the pattern and of each --and and --not pattern in bytes, with a suffix K, M or G like 20M. Patterns over it, like huge alternations from -f, are rejected rather than using up memory. The default is 10M"
+    )]
+    regex_size_limit: Option<String>,
+    #[structopt(
+        long,
+        value_name = "SIZE",
+        help = "The cache size of the lazy DFA for matching each pattern, including --and and --not, in bytes, with a suffix K, M or G. Matching gets slower rather than failing when it is full. The default is 2M"
+    )]
+    dfa_size_limit: Option<String>,
+    #[structopt(
+        short = "F",
+        long,
+        help = "Treat the patterns as literal strings rather than regular expressions. They are still matched by the regex engine, with every character escaped"
+    )]
+    fixed_strings: bool,
+    #[structopt(
+        short = "w",
+        long,
+        help = "Match only whole words, i.e. the pattern surrounded by word boundaries"
+    )]
+    word_regexp: bool,
+    #[structopt(
+        short = "x",
+        long,
+        help = "Match only whole lines. Takes precedence over -w"
+    )]
+    line_regexp: bool,
+    #[structopt(
+        short = "U",
+        long,
+        help = "Let matches span lines, with . matching newlines too. A match is reported at the line it starts and printed with all the lines it spans"
+    )]
+    multiline: bool,
+}
+
+impl Opt {
+    fn log_level(&self) -> LevelFilter {
+        match self.log_level {
+            Some(level) => level,
+            None if self.quiet => LevelFilter::Error,
+            None if self.verbose => LevelFilter::Debug,
+            None => LevelFilter::Info,
+        }
+    }
+}
+
+/// Logs diagnostics of this crate at `level` to stderr as bare messages, like they were printed
+/// before logging. Other crates only log warnings and errors unless asked for less.
+fn init_logger(level: LevelFilter) {
+    env_logger::Builder::new()
+        .filter_level(level.min(LevelFilter::Warn))
+        .filter_module("rust_gitgrep", level)
+        .format(|buf, record| writeln!(buf, "{}", record.args()))
+        .init();
 }
 
 fn main() -> Result<()> {
-    let settings: Settings = Opt::from_args().try_into()?;
+    let opt = Opt::from_args();
+    if opt.type_list {
+        let mut out = io::stdout().lock();
+        for (name, extensions) in FILE_TYPES {
+            writeln!(out, "{}: {}", name, extensions.join(", "))?;
+        }
+        return Ok(());
+    }
+    init_logger(opt.log_level());
+    let exit_code = opt.exit_code;
+    match run(opt) {
+        // Like the shell does for a process killed by SIGINT.
+        Ok(stats) if stats.cancelled => std::process::exit(130),
+        Ok(stats) if exit_code => std::process::exit(if 0 < stats.matches { 0 } else { 1 }),
+        Err(e) if exit_code => {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(2)
+        }
+        result => result.map(|_| ()),
+    }
+}
+
+fn run(opt: Opt) -> Result<Stats> {
+    let Config {
+        settings,
+        output,
+        repos,
+    } = opt.try_into()?;
 
-    eprintln!(
-        "Searching path: {:?} extensions: {:?} ignore_dirs: {:?}",
-        settings.repo, settings.extensions, settings.ignore_dirs
+    let path = if repos.is_empty() {
+        format!("{:?}", settings.repo)
+    } else {
+        format!("{} repos from stdin", repos.len())
+    };
+    info!(
+        "Searching path: {} extensions: {:?} ignore_dirs: {:?}",
+        path, settings.extensions, settings.ignore_dirs
     );
+    if settings.follow && !settings.diff_only {
+        warn!("Warning: --follow has no effect without --diff-only");
+    }
 
-    let _file_list = process_files_git(&settings.repo, &settings)?;
+    // The first Ctrl-C stops the search and prints what is found so far, which can take a while
+    // to come back from a large tree, so the second one exits immediately.
+    let cancel = settings.cancel.clone();
+    ctrlc::set_handler(move || {
+        if cancel.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+    })?;
 
-    Ok(())
+    // The colored crate makes its own guess from the environment, which would be wrong when
+    // writing to a file with --color always or through a pipe.
+    colored::control::set_override(output.color_code);
+
+    let mut out: Box<dyn Write> = match output.output {
+        _ if output.probe => Box::new(io::sink()),
+        Some(ref path) => {
+            Box::new(BufWriter::new(File::create(path).map_err(|e| {
+                anyhow!("Could not open output file {:?}: {}", path, e)
+            })?))
+        }
+        None => Box::new(io::stdout()),
+    };
+
+    let start = Instant::now();
+    let greps = if repos.is_empty() {
+        vec![GitGrep::new(settings.clone())]
+    } else {
+        open_repos(&settings, &repos)
+    };
+    let stats = if let Some(top) = output.count_commits {
+        print_commit_counts(&mut out, &greps, &output, top)?
+    } else if let (Some(_), Some(scope)) = (output.count, output.unique_lines) {
+        print_unique_counts(&mut out, &greps, &output, scope)?
+    } else if let Some(count_by) = output.count {
+        print_counts(&mut out, &greps, &output, count_by)?
+    } else if output.files_without_match {
+        print_files_without_match(&mut out, &greps, &output)?
+    } else if output.files_with_matches {
+        print_files_with_matches(&mut out, &greps, &output)?
+    } else if output.commits_only || output.summary_per_commit {
+        print_commits(&mut out, &greps, &output)?
+    } else if output.interactive {
+        let mut matches = vec![];
+        let stats = search_all(&greps, |entry| {
+            matches.push(entry);
+            Ok(())
+        })?;
+        tui::browse(matches, &output)?;
+        stats
+    } else {
+        let mut seen = HashSet::new();
+        let mut unique = |entry: &MatchEntry| match output.unique_lines {
+            Some(scope) => seen.insert(unique_key(entry, scope)),
+            None => true,
+        };
+        if output.json_array {
+            let mut matches = vec![];
+            let stats = search_all(&greps, |entry| {
+                if unique(&entry) {
+                    matches.push(match_to_json(&entry, &settings));
+                }
+                Ok(())
+            })?;
+            let json = serde_json::json!({
+                "repos": greps
+                    .iter()
+                    .map(|grep| grep.settings().repo.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "patterns": output.patterns,
+                "matches": matches,
+            });
+            writeln!(out, "{}", json)?;
+            stats
+        } else if output.group_by_file {
+            let mut matches = vec![];
+            let stats = search_all(&greps, |entry| {
+                if unique(&entry) {
+                    matches.push(entry);
+                }
+                Ok(())
+            })?;
+            // The sort is stable, so the commits of each path are kept in the order of the walk.
+            matches.sort_by(|a, b| a.path().cmp(b.path()));
+            let mut printer = Printer::new(&mut out, &settings, &output);
+            for entry in &matches {
+                printer.print_match(entry)?;
+            }
+            printer.finish()?;
+            stats
+        } else {
+            let mut printer = Printer::new(&mut out, &settings, &output);
+            if output.ref_names {
+                for grep in &greps {
+                    printer.ref_names.extend(grep.ref_names()?);
+                }
+            }
+            let stats = search_all(&greps, |entry| {
+                if unique(&entry) {
+                    printer.print_match(&entry)?;
+                }
+                Ok(())
+            })?;
+            printer.finish()?;
+            stats
+        }
+    };
+    out.flush()?;
+
+    if stats.cancelled {
+        warn!("Interrupted; the results are partial");
+    }
+    if output.stat {
+        eprintln!(
+            "{} matches in {} lines in {} files in {} commits; searched {} files in {} commits, skipped {} blobs in {:.3}s",
+            stats.matches,
+            stats.matched_lines,
+            stats.files,
+            stats.commits,
+            stats.searched_files,
+            stats.searched_commits,
+            stats.skipped_blobs,
+            start.elapsed().as_secs_f64()
+        );
+        if 0 < stats.duplicates {
+            eprintln!("{} duplicate matches suppressed", stats.duplicates);
+        }
+    }
+    if let Some(ref path) = output.stats_json {
+        let json = serde_json::json!({
+            "matches": stats.matches,
+            "matched_lines": stats.matched_lines,
+            "files": stats.files,
+            "commits": stats.commits,
+            "searched_commits": stats.searched_commits,
+            "searched_trees": stats.searched_trees,
+            "searched_files": stats.searched_files,
+            "skipped_blobs": stats.skipped_blobs,
+            "duplicates": stats.duplicates,
+            "cancelled": stats.cancelled,
+            "elapsed_ms": start.elapsed().as_millis() as u64,
+        });
+        match path {
+            Some(path) => std::fs::write(path, format!("{}\n", json))
+                .map_err(|e| anyhow!("Could not write stats to {:?}: {}", path, e))?,
+            None => eprintln!("{}", json),
+        }
+    }
+
+    Ok(stats)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl std::str::FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(anyhow!("Unknown color choice: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UniqueScope {
+    All,
+    File,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CountBy {
+    Commit,
+    File,
+}
+
+impl std::str::FromStr for CountBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "commit" => Ok(Self::Commit),
+            "file" => Ok(Self::File),
+            _ => Err(anyhow!("Unknown count breakdown: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GroupBy {
+    Commit,
+    File,
+    None,
+}
+
+impl std::str::FromStr for GroupBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "commit" => Ok(Self::Commit),
+            "file" => Ok(Self::File),
+            "none" => Ok(Self::None),
+            _ => Err(anyhow!("Unknown grouping: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PathStyle {
+    Relative,
+    Absolute,
+}
+
+impl std::str::FromStr for PathStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "relative" => Ok(Self::Relative),
+            "absolute" => Ok(Self::Absolute),
+            _ => Err(anyhow!("Unknown path style: {}", s)),
+        }
+    }
+}
+
+/// Options that only affect how the matches are printed.
+struct OutputSettings {
+    color_code: bool,
+    /// Print the commit once above its matches.
+    output_grouping: bool,
+    /// Print the path once above its matches in all commits, with the commit on each line.
+    group_by_file: bool,
+    /// Print the path once above the matches in a file instead of on each line.
+    heading: bool,
+    /// Browse the matches in a terminal UI instead of printing them.
+    interactive: bool,
+    json: bool,
+    /// Print all matches as one JSON document at the end instead of one object per match.
+    json_array: bool,
+    /// The patterns as given, for the metadata of `json_array`.
+    patterns: Vec<String>,
+    /// The file to write the results to, or stdout if not given.
+    output: Option<PathBuf>,
+    /// Print counts of matches instead of the matches themselves, if given.
+    count: Option<CountBy>,
+    /// Count every match rather than matching lines.
+    count_matches: bool,
+    /// Print the number of commits each file has matches in, for at most `top` files if given.
+    count_commits: Option<Option<usize>>,
+    /// Print each distinct line once, or count them with `count`, if given. Lines are told apart
+    /// per file with `Some(UniqueScope::File)`.
+    unique_lines: Option<UniqueScope>,
+    files_with_matches: bool,
+    /// Print only the paths of files without matches.
+    files_without_match: bool,
+    /// Print only the commits with matches.
+    commits_only: bool,
+    /// Print only the commits with matches, with the numbers of files and matches in them.
+    summary_per_commit: bool,
+    /// Qualify file paths with the commit, rather than listing each path once.
+    by_commit: bool,
+    /// Print only the matched part of the line.
+    only_matching: bool,
+    /// Print each matched line once, highlighting all the matches in it.
+    highlight_only: bool,
+    /// Print lines with matches replaced by this template, if given.
+    replace: Option<String>,
+    /// Terminate paths by NUL with `files_with_matches` or `files_without_match`.
+    null: bool,
+    /// Separate fields by this instead of the usual decorations, if given. It is NUL with `null`.
+    field_separator: Option<String>,
+    /// The line between blocks of context lines.
+    context_separator: String,
+    /// Print the column of matches after the line number.
+    column: bool,
+    /// Cut lines longer than this many characters when printing them, if given.
+    max_columns: Option<usize>,
+    /// The number of hex digits to print commit ids with.
+    abbrev: usize,
+    /// The number of hex digits of short commit ids, like `{short_commit}` in templates and the
+    /// commits of `--commits-only`, which is `abbrev` if `--abbrev` is given.
+    short_abbrev: usize,
+    /// Print matches by this template instead of the default layout, if given.
+    format: Option<Template>,
+    /// Print the statistics of the search at the end.
+    stat: bool,
+    /// Write the statistics of the search in JSON at the end, to the file if given or to stderr.
+    stats_json: Option<Option<PathBuf>>,
+    /// Print nothing, since only whether anything matches is asked by the exit code.
+    probe: bool,
+    /// Print commits with the names of references pointing to them.
+    ref_names: bool,
+}
+
+impl OutputSettings {
+    /// Formats a commit id abbreviated to `abbrev` digits.
+    fn commit_id(&self, commit: Oid) -> String {
+        let mut id = commit.to_string();
+        id.truncate(self.abbrev);
+        id
+    }
+
+    /// Formats a commit id abbreviated to `short_abbrev` digits.
+    fn short_commit_id(&self, commit: Oid) -> String {
+        let mut id = commit.to_string();
+        id.truncate(self.short_abbrev);
+        id
+    }
+}
+
+/// Prepares the search of each repo read from stdin, prefixing the paths of matches with the repo
+/// path as given, or with its absolute path if the settings already have an absolute prefix by
+/// `--path-style absolute`. Repos that cannot be found are skipped with a warning, so that one
+/// mistake does not stop a batch.
+fn open_repos(settings: &Settings, repos: &[String]) -> Vec<GitGrep> {
+    let mut greps = vec![];
+    for repo in repos {
+        match canonicalize(repo) {
+            Ok(path) => greps.push(GitGrep::new(Settings {
+                path_prefix: if settings.path_prefix.is_absolute() {
+                    path.clone()
+                } else {
+                    PathBuf::from(repo)
+                },
+                repo: path,
+                ..settings.clone()
+            })),
+            Err(e) => warn!("Skipping repo {:?}: {}", repo, e),
+        }
+    }
+    greps
+}
+
+/// Searches the repos one after another, adding up the statistics.
+fn search_all(
+    greps: &[GitGrep],
+    mut callback: impl FnMut(MatchEntry) -> Result<()>,
+) -> Result<Stats> {
+    let mut stats = Stats::default();
+    for grep in greps {
+        if grep.settings().cancel.load(Ordering::Relaxed) {
+            break;
+        }
+        stats.add(&grep.search_with(&mut callback)?);
+    }
+    Ok(stats)
+}
+
+/// Counts matches or matching lines as they arrive, which is possible without any map since they
+/// are grouped by commit, then by file and then by line.
+fn print_counts(
+    out: &mut dyn Write,
+    greps: &[GitGrep],
+    output: &OutputSettings,
+    count_by: CountBy,
+) -> Result<Stats> {
+    let mut counts: Vec<(Oid, Option<PathBuf>, usize)> = vec![];
+    let mut total = 0;
+    let mut last_line = None;
+    let stats = search_all(greps, |entry| {
+        let line = (
+            entry.commit(),
+            entry.path().to_path_buf(),
+            entry.line_number(),
+        );
+        if !output.count_matches && last_line.as_ref() == Some(&line) {
+            return Ok(());
+        }
+        last_line = Some(line);
+        total += 1;
+        let path = if count_by == CountBy::File {
+            Some(entry.path().to_path_buf())
+        } else {
+            None
+        };
+        match counts.last_mut() {
+            Some((commit, last_path, count)) if *commit == entry.commit() && *last_path == path => {
+                *count += 1
+            }
+            _ => counts.push((entry.commit(), path, 1)),
+        }
+        Ok(())
+    })?;
+
+    for (commit, path, count) in counts {
+        let commit = if output.color_code {
+            output.commit_id(commit).bright_blue().to_string()
+        } else {
+            output.commit_id(commit)
+        };
+        match path {
+            Some(path) if output.color_code => writeln!(
+                out,
+                "{} {}: {}",
+                commit,
+                path.to_string_lossy().green(),
+                count
+            )?,
+            Some(path) => writeln!(out, "{} {}: {}", commit, path.to_string_lossy(), count)?,
+            None => writeln!(out, "{}: {}", commit, count)?,
+        }
+    }
+    writeln!(out, "Total: {}", total)?;
+    Ok(stats)
+}
+
+/// Counts the distinct commits with matches in each file, which come in one after another since
+/// matches are grouped by commit and then by file.
+fn print_commit_counts(
+    out: &mut dyn Write,
+    greps: &[GitGrep],
+    output: &OutputSettings,
+    top: Option<usize>,
+) -> Result<Stats> {
+    let mut counts: HashMap<PathBuf, usize> = HashMap::new();
+    let mut last = None;
+    let stats = search_all(greps, |entry| {
+        let file = (entry.commit(), entry.path().to_path_buf());
+        if last.as_ref() != Some(&file) {
+            *counts.entry(file.1.clone()).or_default() += 1;
+            last = Some(file);
+        }
+        Ok(())
+    })?;
+
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|(a_path, a), (b_path, b)| b.cmp(a).then_with(|| a_path.cmp(b_path)));
+    for (path, count) in counts.into_iter().take(top.unwrap_or(usize::MAX)) {
+        if output.color_code {
+            writeln!(
+                out,
+                "{} {}",
+                count.to_string().bright_yellow(),
+                path.to_string_lossy().green()
+            )?;
+        } else {
+            writeln!(out, "{} {}", count, path.to_string_lossy())?;
+        }
+    }
+    Ok(stats)
+}
+
+/// Identifies a matched line for `--unique-lines`.
+fn unique_key(entry: &MatchEntry, scope: UniqueScope) -> (Option<PathBuf>, String) {
+    let path = match scope {
+        UniqueScope::All => None,
+        UniqueScope::File => Some(entry.path().to_path_buf()),
+    };
+    (path, entry.line().to_owned())
+}
+
+/// Counts how many times each distinct line occurred, in the order they are first found.
+fn print_unique_counts(
+    out: &mut dyn Write,
+    greps: &[GitGrep],
+    output: &OutputSettings,
+    scope: UniqueScope,
+) -> Result<Stats> {
+    let mut indices: HashMap<_, usize> = HashMap::new();
+    let mut counts: Vec<((Option<PathBuf>, String), usize)> = vec![];
+    let mut total = 0;
+    let mut last_line = None;
+    let stats = search_all(greps, |entry| {
+        let line = (
+            entry.commit(),
+            entry.path().to_path_buf(),
+            entry.line_number(),
+        );
+        if !output.count_matches && last_line.as_ref() == Some(&line) {
+            return Ok(());
+        }
+        last_line = Some(line);
+        total += 1;
+        let key = unique_key(&entry, scope);
+        match indices.get(&key) {
+            Some(&i) => counts[i].1 += 1,
+            None => {
+                indices.insert(key.clone(), counts.len());
+                counts.push((key, 1));
+            }
+        }
+        Ok(())
+    })?;
+
+    for ((path, line), count) in counts {
+        match path {
+            Some(path) if output.color_code => writeln!(
+                out,
+                "{} {}: {}",
+                count.to_string().bright_yellow(),
+                path.to_string_lossy().green(),
+                line
+            )?,
+            Some(path) => writeln!(out, "{} {}: {}", count, path.to_string_lossy(), line)?,
+            None if output.color_code => {
+                writeln!(out, "{} {}", count.to_string().bright_yellow(), line)?
+            }
+            None => writeln!(out, "{} {}", count, line)?,
+        }
+    }
+    writeln!(out, "Total: {}", total)?;
+    Ok(stats)
+}
+
+fn print_files_with_matches(
+    out: &mut dyn Write,
+    greps: &[GitGrep],
+    output: &OutputSettings,
+) -> Result<Stats> {
+    let mut printed = HashSet::new();
+    let stats = search_all(greps, |entry| {
+        let commit = if output.by_commit {
+            Some(entry.commit())
+        } else {
+            None
+        };
+        if !printed.insert((commit, entry.path().to_path_buf())) {
+            return Ok(());
+        }
+        let path = entry.path().to_string_lossy();
+        let terminator = if output.null { '\0' } else { '\n' };
+        match commit {
+            Some(commit) if output.color_code => write!(
+                out,
+                "{} {}{}",
+                output.commit_id(commit).bright_blue(),
+                path.green(),
+                terminator
+            )?,
+            Some(commit) => write!(out, "{} {}{}", output.commit_id(commit), path, terminator)?,
+            None if output.color_code => write!(out, "{}{}", path.green(), terminator)?,
+            None => write!(out, "{}{}", path, terminator)?,
+        }
+        Ok(())
+    })?;
+    Ok(stats)
+}
+
+/// Prints the files that pass the filters but have no match, each path once in the order they
+/// are found. The paths with matches are collected first, and then the files are listed by another
+/// walk like `--list-files`.
+fn print_files_without_match(
+    out: &mut dyn Write,
+    greps: &[GitGrep],
+    output: &OutputSettings,
+) -> Result<Stats> {
+    let mut matched = HashSet::new();
+    let stats = search_all(greps, |entry| {
+        matched.insert(entry.path().to_path_buf());
+        Ok(())
+    })?;
+
+    let listings: Vec<_> = greps
+        .iter()
+        .map(|grep| {
+            GitGrep::new(Settings {
+                list_files: true,
+                search_messages: false,
+                max_count: None,
+                ..grep.settings().clone()
+            })
+        })
+        .collect();
+    let mut printed = HashSet::new();
+    let terminator = if output.null { '\0' } else { '\n' };
+    search_all(&listings, |entry| {
+        if matched.contains(entry.path()) || !printed.insert(entry.path().to_path_buf()) {
+            return Ok(());
+        }
+        let path = entry.path().to_string_lossy();
+        if output.color_code {
+            write!(out, "{}{}", path.green(), terminator)?;
+        } else {
+            write!(out, "{}{}", path, terminator)?;
+        }
+        Ok(())
+    })?;
+    Ok(stats)
+}
+
+/// Prints the commits with matches after the search, since they are sorted by date rather than
+/// in the order of the walk. With `summary_per_commit`, the date is replaced by the numbers of
+/// files and matches at the end. Commits are looked up in the repository each match came from,
+/// which is a submodule for the matches in submodules, and counted per repository.
+fn print_commits(out: &mut dyn Write, greps: &[GitGrep], output: &OutputSettings) -> Result<Stats> {
+    let mut stats = Stats::default();
+    let mut commits = vec![];
+    for grep in greps {
+        // The time, the date and the summary of each commit by its repository, and the files and
+        // the number of matches in it.
+        let mut found: HashMap<(Option<PathBuf>, Oid), CommitSummary> = HashMap::new();
+        let mut repos: HashMap<Option<PathBuf>, Repository> = HashMap::new();
+        stats.add(&search_all(std::slice::from_ref(grep), |entry| {
+            let submodule = entry.submodule().map(Path::to_path_buf);
+            let key = (submodule.clone(), entry.commit());
+            if !found.contains_key(&key) {
+                let repo = match repos.entry(submodule) {
+                    Entry::Occupied(repo) => repo.into_mut(),
+                    Entry::Vacant(vacant) => vacant.insert(grep.repository(&entry)?),
+                };
+                let commit = repo.find_commit(entry.commit())?;
+                let time = commit.time();
+                let date = FixedOffset::east_opt(time.offset_minutes() * 60)
+                    .and_then(|offset| offset.timestamp_opt(time.seconds(), 0).single())
+                    .map_or_else(String::new, |date| date.format("%Y-%m-%d").to_string());
+                let summary = commit.summary().unwrap_or_default().to_owned();
+                found.insert(
+                    key.clone(),
+                    (time.seconds(), date, summary, HashSet::new(), 0),
+                );
+            }
+            let (.., files, matches) = found.get_mut(&key).unwrap();
+            files.insert(entry.path().to_path_buf());
+            *matches += 1;
+            Ok(())
+        })?);
+        for ((_, id), (time, date, summary, files, matches)) in found {
+            commits.push((
+                time,
+                output.short_commit_id(id),
+                date,
+                summary,
+                files.len(),
+                matches,
+            ));
+        }
+    }
+    // The id breaks ties so that the order is stable.
+    commits.sort();
+    if !greps.first().is_some_and(|grep| grep.settings().reverse) {
+        commits.reverse();
+    }
+    let plural = |count: usize, one: &str, many: &str| match count {
+        1 => format!("1 {}", one),
+        count => format!("{} {}", count, many),
+    };
+    for (_, id, date, summary, files, matches) in commits {
+        let id = if output.color_code {
+            id.bright_blue().to_string()
+        } else {
+            id
+        };
+        if output.summary_per_commit {
+            writeln!(
+                out,
+                "{}  {}  ({}, {})",
+                id,
+                summary,
+                plural(files, "file", "files"),
+                plural(matches, "match", "matches")
+            )?;
+        } else {
+            writeln!(out, "{} {} {}", id, date, summary)?;
+        }
+    }
+    Ok(stats)
+}
+
+/// The time and the date of a commit, its summary, its files with matches and the number of the
+/// matches, for [`print_commits`].
+type CommitSummary = (i64, String, String, HashSet<PathBuf>, usize);
+
+struct Config {
+    settings: Settings,
+    output: OutputSettings,
+    /// The repos read from stdin with `--repo-from-stdin`, which are searched instead of the one
+    /// in `settings`.
+    repos: Vec<String>,
 }
 
-#[allow(dead_code)]
-struct MatchEntry {
-    commit: Oid,
-    path: PathBuf,
-    start: usize,
-    end: usize,
+/// Converts a match to JSON. Offsets are given both in bytes and in characters, since consumers
+/// disagree on which they expect. `start` and `end` are the byte offsets in the file, and columns
+/// are 1-based in the line. `column` is in characters like `char_column`. `char_line` and
+/// `char_end_line` are the same as the line numbers, since lines are split at newlines whichever
+/// unit the offsets are in, and are given so that every position has its character fields.
+fn match_to_json(entry: &MatchEntry, settings: &Settings) -> serde_json::Value {
+    let line = entry.line();
+    let (start, end) = match_range(entry);
+    let chars = |i: usize| line[..i].chars().count();
+    serde_json::json!({
+        "commit": entry.commit().to_string(),
+        "path": entry.path().to_string_lossy(),
+        "line_number": entry.line_number(),
+        "end_line_number": entry.end_line_number(),
+        "column": entry.column(),
+        "start": entry.start(),
+        "end": entry.end(),
+        "byte_start": entry.start(),
+        "byte_end": entry.end(),
+        "byte_column": start + 1,
+        "byte_end_column": end + 1,
+        "char_line": entry.line_number(),
+        "char_end_line": entry.end_line_number(),
+        "char_column": chars(start) + 1,
+        "char_end_column": chars(end) + 1,
+        "submatches": submatches(entry, settings).map(|submatches| {
+            submatches
+                .into_iter()
+                .map(|(group, start, end)| {
+                    serde_json::json!({
+                        "group": group,
+                        "text": &line[start..end],
+                        "byte_column": start + 1,
+                        "byte_end_column": end + 1,
+                        "char_column": chars(start) + 1,
+                        "char_end_column": chars(end) + 1,
+                    })
+                })
+                .collect::<Vec<_>>()
+        }),
+        "line": line,
+        "binary": entry.binary(),
+        "hunk": entry.hunk(),
+        "more_matches": entry.more_matches(),
+        "change": entry.change().map(|change| match change {
+            LineChange::Added => "added",
+            LineChange::Removed => "removed",
+            LineChange::Unchanged => "unchanged",
+        }),
+    })
 }
 
-#[derive(Debug)]
-struct Settings {
-    pattern: Regex,
-    repo: PathBuf,
-    branch: Option<String>,
-    all: bool,
-    depth: Option<usize>,
-    once_file: bool,
-    color_code: bool,
-    output_grouping: bool,
-    verbose: bool,
-    extensions: HashSet<OsString>,
-    ignore_dirs: HashSet<OsString>,
+/// Returns the capture groups of the match that took part in it, as the group index and the
+/// byte range in the line, or `None` if the pattern has no groups.
+fn submatches(entry: &MatchEntry, settings: &Settings) -> Option<Vec<(usize, usize, usize)>> {
+    let pattern = &settings.pattern;
+    if pattern.captures_len() <= 1 || entry.binary() || settings.invert_match {
+        return None;
+    }
+    let (start, end) = match_range(entry);
+    let mut locations = pattern.capture_locations();
+    pattern.captures_read_at(&mut locations, entry.line(), start)?;
+    // A match cut at the end of the line is not the same match in the line alone.
+    if locations.get(0) != Some((start, end)) {
+        return Some(vec![]);
+    }
+    Some(
+        (1..locations.len())
+            .filter_map(|group| {
+                let (start, end) = locations.get(group)?;
+                Some((group, start, end))
+            })
+            .collect(),
+    )
 }
 
 // It's a bit awkward to convert from Opt to Settings, but some settings are hard to write
 // conversion code inside structopt annotations.
-impl TryFrom<Opt> for Settings {
+impl TryFrom<Opt> for Config {
     type Error = anyhow::Error;
 
-    fn try_from(src: Opt) -> std::result::Result<Self, Self::Error> {
-        let default_exts = [
-            ".sh", ".js", ".tcl", ".pl", ".py", ".rb", ".c", ".cpp", ".h", ".rc", ".rci", ".dlg",
-            ".pas", ".dpr", ".cs", ".rs",
-        ];
-        let default_ignore_dirs = [".hg", ".svn", ".git", ".bzr", "node_modules", "target"]; // Probably we could ignore all directories beginning with a dot.
+    fn try_from(mut src: Opt) -> std::result::Result<Self, Self::Error> {
+        // With -e or -f, the positional pattern is not given, so the first positional argument is
+        // the repo.
+        let patterns = if src.list_files {
+            if src.repo.is_some() {
+                return Err(anyhow!("The pattern should not be given with --list-files"));
+            }
+            src.repo = src.pattern.take().map(PathBuf::from);
+            vec![]
+        } else if src.regexp.is_empty() && src.file.is_empty() {
+            vec![src.pattern.take().unwrap()]
+        } else if src.repo.is_some() {
+            return Err(anyhow!("The pattern should not be given with -e or -f"));
+        } else {
+            src.repo = src.pattern.take().map(PathBuf::from);
+            let mut patterns = std::mem::take(&mut src.regexp);
+            for file in &src.file {
+                patterns.extend(read_patterns(file)?);
+            }
+            patterns
+        };
+        if src.vimgrep {
+            src.format = Some(VIMGREP_FORMAT.to_string());
+        }
 
-        Ok(Self {
-            pattern: Regex::new(&src.pattern)
-                .map_err(|e| anyhow!("Error in regex compilation: {:?}", e))?,
-            repo: canonicalize(
-                src.repo.unwrap_or_else(|| {
-                    PathBuf::from(env::current_dir().unwrap().to_str().unwrap())
-                }),
-            )
-            .expect("Canonicalized path"),
+        let repos = if src.repo_from_stdin {
+            if src.repo.is_some() {
+                return Err(anyhow!(
+                    "The repo should not be given with --repo-from-stdin"
+                ));
+            }
+            io::stdin()
+                .lines()
+                .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+                .map(|line| Ok(line?.trim().to_owned()))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            vec![]
+        };
+
+        let (repo, subdir) = match src.repo.take() {
+            Some(repo) => {
+                let repo =
+                    canonicalize(repo).map_err(|e| anyhow!("Could not find the repo: {}", e))?;
+                split_subdir(repo, src.bare, src.subdir.take())?
+            }
+            // Like git, the repo is found from the current directory up, and the whole of it is
+            // searched unless --subdir is given.
+            None => {
+                let dir = canonicalize(env::current_dir()?)
+                    .map_err(|e| anyhow!("Could not find the repo: {}", e))?;
+                match src.subdir.take() {
+                    Some(subdir) => split_subdir(dir, src.bare, Some(subdir))?,
+                    None => (split_subdir(dir, src.bare, None)?.0, None),
+                }
+            }
+        };
+
+        // Options on the command line override the ones in rc files.
+        let mut rc = RcFile::load(&repo)?;
+        if !src.extensions.is_empty() {
+            rc.extensions.clear();
+        }
+        if !src.ignore_dirs.is_empty() {
+            rc.ignore_dirs.clear();
+        }
+        let color = match src.color {
+            Some(color) => color,
+            None => rc.color.as_deref().unwrap_or("auto").parse()?,
+        };
+        let context = src.context.or(rc.context);
+        let probe = src.quiet && src.exit_code;
+        // An id is 40 digits. Git does not go below 4 digits either.
+        let abbrev = src
+            .abbrev
+            .map(|abbrev| abbrev.unwrap_or(DEFAULT_ABBREV).clamp(4, 40));
+        let no_ignore_defaults = src.no_ignore_defaults;
+        if let (Some(min), Some(max)) = (src.min_line, src.max_line) {
+            if max < min {
+                return Err(anyhow!("--max-line {} is before --min-line {}", max, min));
+            }
+        }
+        if src.count_by.is_some() && !src.count && !src.count_matches {
+            return Err(anyhow!("--count-by needs --count or --count-matches"));
+        }
+        let no_default_extensions = src.no_default_extensions || !src.types.is_empty();
+        let path_prefix = if src.path_style == Some(PathStyle::Absolute) {
+            repo.clone()
+        } else {
+            PathBuf::new()
+        };
+
+        let settings = Settings {
+            pattern: build_pattern(&src, &patterns)?,
+            and_patterns: build_line_filters(&src, &src.and, "--and")?,
+            not_patterns: build_line_filters(&src, &src.not, "--not")?,
+            repo,
+            bare: src.bare,
             branch: src.branch,
-            all: src.all,
+            all: src.all || src.tips_only,
+            tags: src.tags,
+            stash: src.stash,
+            reflog: src.reflog,
+            range: match src.range {
+                Some(range) if !range.contains("..") || range.contains("...") => {
+                    return Err(anyhow!("Range should be given as A..B: {:?}", range))
+                }
+                range => range,
+            },
+            since_commit: src.since_commit,
+            no_history: src.no_history || src.tips_only,
             depth: src.depth,
-            once_file: !src.no_once_file,
-            color_code: !src.no_color_code,
-            output_grouping: !src.no_output_grouping,
-            verbose: src.verbose,
-            extensions: if src.extensions.is_empty() {
-                default_exts.iter().map(|ext| ext[1..].into()).collect()
+            first_parent: src.first_parent,
+            sparse: src.sparse,
+            sort: src.sort.unwrap_or(CommitOrder::Topological),
+            // A path is in the tip, so its older versions would not be searched.
+            once_file: !src.no_once_file && !src.deleted_only && !src.count_commits,
+            first_introduced: src.first_introduced,
+            reverse: src.reverse,
+            dedup: src.dedup,
+            deleted_only: src.deleted_only,
+            diff_only: src.diff_only,
+            follow: src.follow,
+            invert_match: src.invert_match,
+            one_per_line: src.one_per_line,
+            multiline: src.multiline,
+            // Context lines make no sense when only the matched parts are printed, and
+            // templates have no place for them.
+            before_context: match src.before_context.or(context) {
+                Some(lines) if !src.only_matching && src.format.is_none() => lines,
+                _ => 0,
+            },
+            after_context: match src.after_context.or(context) {
+                Some(lines) if !src.only_matching && src.format.is_none() => lines,
+                _ => 0,
+            },
+            threads: src.threads,
+            blob_cache: src.blob_cache.unwrap_or(0),
+            // The first match is enough to tell the exit code.
+            max_count: if probe { Some(1) } else { src.max_count },
+            max_per_file: src.max_per_file,
+            min_line: src.min_line,
+            max_line: src.max_line,
+            cancel: Arc::new(AtomicBool::new(false)),
+            authors: compile_signature_patterns(&src.author, "author")?,
+            authors_not: compile_signature_patterns(&src.author_not, "author")?,
+            committers: compile_signature_patterns(&src.committer, "committer")?,
+            since: src
+                .since
+                .as_deref()
+                .map(|date| parse_date(date, false))
+                .transpose()?,
+            until: src
+                .until
+                .as_deref()
+                .map(|date| parse_date(date, true))
+                .transpose()?,
+            encoding: src
+                .encoding
+                .as_deref()
+                .map(|label| {
+                    Encoding::for_label(label.as_bytes())
+                        .ok_or_else(|| anyhow!("Unknown encoding: {:?}", label))
+                })
+                .transpose()?,
+            lossy: src.lossy,
+            encoding_detect: src.encoding_detect,
+            binary_files: if src.text {
+                BinaryFiles::Text
             } else {
-                default_exts
-                    .iter()
-                    .map(|ext| ext[1..].into())
-                    .chain(src.extensions.iter().map(|ext| ext[1..].into()))
-                    .collect()
+                src.binary_files.unwrap_or(BinaryFiles::WithoutMatch)
             },
-            ignore_dirs: if src.ignore_dirs.is_empty() {
-                default_ignore_dirs.iter().map(|ext| ext.into()).collect()
+            show_hunk: src.show_hunk,
+            diff_lines: src.diff_lines,
+            max_filesize: src.max_filesize.as_deref().map(parse_size).transpose()?,
+            verbose: log_enabled!(Level::Debug),
+            progress: src.progress && !src.quiet,
+            extensions: DEFAULT_EXTENSIONS
+                .iter()
+                .filter(|_| !no_default_extensions)
+                .map(|ext| ext.into())
+                .chain(
+                    src.extensions
+                        .iter()
+                        .chain(&rc.extensions)
+                        .map(|ext| ext.trim_start_matches('.').into()),
+                )
+                .chain(type_extensions(&src.types)?)
+                .collect(),
+            exclude_extensions: type_extensions(&src.types_not)?.into_iter().collect(),
+            ignore_dirs: DEFAULT_IGNORE_DIRS
+                .iter()
+                .filter(|_| !no_ignore_defaults)
+                .map(|ext| ext.into())
+                .chain(src.ignore_dirs.iter().map(|ext| ext.into()))
+                .chain(rc.ignore_dirs.iter().map(|ext| ext.into()))
+                .collect(),
+            include: if src.include.is_empty() {
+                None
             } else {
-                default_ignore_dirs
-                    .iter()
-                    .map(|ext| ext.into())
-                    .chain(src.ignore_dirs.iter().map(|ext| ext.into()))
-                    .collect()
+                Some(build_globset(&src.include)?)
+            },
+            exclude: build_globset(&src.exclude)?,
+            ignore_file: build_ignore_file(&src.ignore_file)?,
+            pre: src.pre,
+            pre_glob: if src.pre_glob.is_empty() {
+                None
+            } else {
+                Some(build_globset(&src.pre_glob)?)
+            },
+            path_pattern: src
+                .path_pattern
+                .as_deref()
+                .map(|pattern| {
+                    Regex::new(pattern)
+                        .map_err(|e| anyhow!("Error in path regex compilation: {:?}", e))
+                })
+                .transpose()?,
+            path_not_pattern: src
+                .path_not_pattern
+                .as_deref()
+                .map(|pattern| {
+                    Regex::new(pattern)
+                        .map_err(|e| anyhow!("Error in path regex compilation: {:?}", e))
+                })
+                .transpose()?,
+            search_messages: src.search_messages,
+            name_search: src.name_search,
+            list_files: src.list_files,
+            respect_gitignore: src.respect_gitignore,
+            recurse_submodules: src.recurse_submodules,
+            path_prefix,
+            subdir,
+        };
+
+        let group_by = src.group_by.unwrap_or(GroupBy::Commit);
+        Ok(Self {
+            settings,
+            repos,
+            output: OutputSettings {
+                color_code: !src.json
+                    && !src.json_array
+                    && !src.null
+                    && match color {
+                        _ if src.no_color_code => false,
+                        ColorChoice::Auto => src.output.is_none() && io::stdout().is_terminal(),
+                        ColorChoice::Always => true,
+                        ColorChoice::Never => false,
+                    },
+                output_grouping: !src.no_output_grouping && group_by == GroupBy::Commit,
+                group_by_file: group_by == GroupBy::File,
+                heading: src.heading
+                    && !src.no_heading
+                    && !src.no_output_grouping
+                    && group_by == GroupBy::Commit
+                    && !src.null
+                    && src.field_separator.is_none(),
+                interactive: src.interactive,
+                json: src.json,
+                json_array: src.json_array,
+                patterns,
+                output: src.output,
+                files_with_matches: src.files_with_matches || src.list_files,
+                files_without_match: src.files_without_match,
+                commits_only: src.commits_only,
+                summary_per_commit: src.summary_per_commit,
+                by_commit: src.by_commit,
+                only_matching: src.only_matching,
+                highlight_only: src.highlight_only,
+                replace: src.replace,
+                null: src.null,
+                field_separator: if src.null {
+                    Some("\0".to_owned())
+                } else {
+                    src.field_separator
+                },
+                context_separator: src.context_separator.unwrap_or_else(|| "--".to_owned()),
+                column: src.column,
+                max_columns: src.max_columns,
+                abbrev: abbrev.unwrap_or(40),
+                short_abbrev: abbrev.unwrap_or(SHORT_COMMIT_LEN),
+                format: src.format.as_deref().map(Template::parse).transpose()?,
+                stat: src.stat,
+                stats_json: src.stats_json,
+                probe,
+                ref_names: src.tips_only,
+                count: match src.count_by {
+                    _ if !src.count && !src.count_matches => None,
+                    count_by => Some(count_by.unwrap_or(CountBy::Commit)),
+                },
+                count_matches: src.count_matches,
+                count_commits: if src.count_commits {
+                    Some(src.top)
+                } else {
+                    None
+                },
+                unique_lines: match (src.unique_lines, src.unique_per_file) {
+                    (false, _) => None,
+                    (true, false) => Some(UniqueScope::All),
+                    (true, true) => Some(UniqueScope::File),
+                },
             },
         })
     }
 }
 
-struct ProcessTree<'a> {
-    settings: &'a Settings,
-    repo: &'a Repository,
-    checked_paths: HashSet<PathBuf>,
-    checked_blobs: HashSet<Oid>,
-    checked_trees: HashSet<Oid>,
-    walked: usize,
-    skipped_blobs: usize,
-    all_matches: Vec<MatchEntry>,
-}
-
-impl<'a> ProcessTree<'a> {
-    fn process(&mut self, tree: &Tree, commit: &Commit, path: &Path, visited: &mut bool) {
-        if self.checked_trees.contains(&tree.id()) {
-            return;
-        }
-        self.checked_trees.insert(tree.id());
-        self.walked += 1;
-
-        for entry in tree {
-            match (|| {
-                let name = entry.name()?;
-                let entry_path = path.join(name);
-
-                // We want to match with absolute path from root, but it seems impossible with `tree.walk`.
-                if self.settings.once_file && self.checked_paths.contains(&entry_path) {
-                    return None;
-                }
-                self.checked_paths.insert(entry_path.clone());
-
-                let obj = match entry.to_object(&self.repo) {
-                    Ok(obj) => obj,
-                    Err(e) => {
-                        eprintln!("couldn't get_object: {:?}", e);
-                        return None;
-                    }
-                };
-                if obj.kind() == Some(ObjectType::Tree) {
-                    self.process(obj.as_tree()?, commit, &entry_path, visited);
-                    return None;
-                }
-                if entry.kind() != Some(ObjectType::Blob)
-                    || self.settings.ignore_dirs.contains(&OsString::from(name))
-                {
-                    return None;
-                }
-
-                let blob = obj.peel_to_blob().ok()?;
-                if blob.is_binary() {
-                    return None;
-                }
-                let ext = PathBuf::from(name).extension()?.to_owned();
-                if !self.settings.extensions.contains(&ext.to_ascii_lowercase()) {
-                    return None;
-                }
-
-                if self.checked_blobs.contains(&blob.id()) {
-                    self.skipped_blobs += 1;
-                    return None;
-                }
-
-                self.checked_blobs.insert(blob.id());
-                let ret = process_file(self.settings, commit, blob.content(), &entry_path, visited);
-                Some(ret)
-            })() {
-                Some(matches) => {
-                    self.all_matches.extend(matches);
-                }
-                _ => (),
-            }
+/// Splits a directory in the working tree of a repo into the root of the working tree and the
+/// path to the directory from it, joined with `subdir` if given.
+fn split_subdir(
+    path: PathBuf,
+    bare: bool,
+    subdir: Option<PathBuf>,
+) -> Result<(PathBuf, Option<PathBuf>)> {
+    let root = match Repository::discover(&path) {
+        Ok(repo) if !bare => repo.workdir().map(canonicalize).transpose()?,
+        _ => None,
+    };
+    let (root, dir) = match root {
+        Some(root) if root != path => {
+            let dir = path.strip_prefix(&root)?.to_path_buf();
+            (root, dir)
+        }
+        _ => (path, PathBuf::new()),
+    };
+    let dir = match subdir {
+        Some(subdir) => dir.join(subdir),
+        None => dir,
+    };
+    // Trees have no `.` or `..` entries, so the path should be normalized.
+    let mut normalized = PathBuf::new();
+    for component in dir.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => (),
+            Component::ParentDir if normalized.pop() => (),
+            _ => return Err(anyhow!("The directory is outside of the repo: {:?}", dir)),
         }
     }
+    if normalized.as_os_str().is_empty() {
+        Ok((root, None))
+    } else {
+        Ok((root, Some(normalized)))
+    }
+}
+
+fn compile_signature_patterns(patterns: &[String], role: &str) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern).map_err(|e| anyhow!("Error in {} regex compilation: {:?}", role, e))
+        })
+        .collect()
+}
+
+fn read_patterns(file: &Path) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(file)
+        .map_err(|e| anyhow!("Could not read patterns from {:?}: {}", file, e))?;
+    Ok(text
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_owned())
+        .collect())
 }
 
-fn process_files_git(_root: &Path, settings: &Settings) -> Result<Vec<MatchEntry>> {
-    let repo = Repository::open(&settings.repo)?;
-    let reference = if let Some(ref branch) = settings.branch {
-        repo.resolve_reference_from_short_name(&branch)?
+/// Combines the patterns into a single alternation, so that a line is reported once even if
+/// several patterns match it.
+fn build_pattern(src: &Opt, patterns: &[String]) -> Result<Regex> {
+    let patterns: Vec<_> = if src.fixed_strings {
+        patterns
+            .iter()
+            .map(|pattern| regex::escape(pattern))
+            .collect()
     } else {
-        repo.head()?
+        patterns.to_vec()
     };
-
-    let mut process_tree = ProcessTree {
-        settings,
-        repo: &repo,
-        checked_paths: HashSet::new(),
-        checked_blobs: HashSet::new(),
-        checked_trees: HashSet::new(),
-        walked: 0,
-        skipped_blobs: 0,
-        all_matches: vec![],
+    let pattern = if patterns.is_empty() {
+        // Like grep, no patterns, as from an empty file of -f, match nothing rather than the
+        // empty alternation matching everything.
+        r"\b\B".to_owned()
+    } else if let [pattern] = &patterns[..] {
+        pattern.clone()
+    } else {
+        patterns
+            .iter()
+            .map(|pattern| format!("(?:{})", pattern))
+            .collect::<Vec<_>>()
+            .join("|")
     };
-    let mut checked_commits = HashMap::new();
-    let mut iter = 0;
-
-    let mut next_refs = if settings.all {
-        repo.references()?
-            .map(|refs| refs.and_then(|refb| refb.peel_to_commit()))
-            .collect::<std::result::Result<Vec<_>, _>>()?
+    // The group keeps alternations inside the anchors or the boundaries. Anchors are in multiline
+    // mode to match at every line, but only them, not to change the meaning of the pattern.
+    let pattern = if src.line_regexp {
+        format!("(?m:^)(?:{})(?m:$)", pattern)
+    } else if src.word_regexp {
+        format!(r"\b(?:{})\b", pattern)
     } else {
-        vec![reference.peel_to_commit()?]
+        pattern
     };
-    loop {
-        for commit in &next_refs {
-            if checked_commits.contains_key(&commit.id()) {
-                continue;
+    let smart_case = src.smart_case
+        && !patterns.iter().any(|pattern| {
+            if src.fixed_strings {
+                pattern.chars().any(|c| c.is_uppercase())
+            } else {
+                has_uppercase_literal(pattern)
             }
-            let entry = checked_commits.entry(commit.id()).or_insert(false);
+        });
+    let mut builder = RegexBuilder::new(&pattern);
+    builder
+        .case_insensitive(src.ignore_case || smart_case)
+        .dot_matches_new_line(src.multiline);
+    set_size_limits(src, &mut builder)?;
+    builder.build().map_err(|e| match e {
+        regex::Error::CompiledTooBig(limit) => anyhow!(
+            "The pattern is too large to compile within {} bytes. Simplify it or raise --regex-size-limit",
+            limit
+        ),
+        e => anyhow!("Error in regex compilation: {:?}", e),
+    })
+}
 
-            let tree = if let Ok(tree) = commit.tree() {
-                tree
+/// Compiles the patterns of `--and` or `--not` with the flags of the main pattern. They are
+/// matched against single lines, so multiline and the whole line or word anchors do not apply.
+fn build_line_filters(src: &Opt, patterns: &[String], option: &str) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            let smart_case = src.smart_case
+                && !if src.fixed_strings {
+                    pattern.chars().any(|c| c.is_uppercase())
+                } else {
+                    has_uppercase_literal(pattern)
+                };
+            let pattern = if src.fixed_strings {
+                regex::escape(pattern)
             } else {
-                continue;
+                pattern.clone()
             };
+            let mut builder = RegexBuilder::new(&pattern);
+            builder.case_insensitive(src.ignore_case || smart_case);
+            set_size_limits(src, &mut builder)?;
+            builder.build().map_err(|e| match e {
+                regex::Error::CompiledTooBig(limit) => anyhow!(
+                    "The pattern of {} is too large to compile within {} bytes. Simplify it or raise --regex-size-limit",
+                    option,
+                    limit
+                ),
+                e => anyhow!("Error in regex compilation of {}: {:?}", option, e),
+            })
+        })
+        .collect()
+}
+
+/// Applies `--regex-size-limit` and `--dfa-size-limit` to a pattern.
+fn set_size_limits(src: &Opt, builder: &mut RegexBuilder) -> Result<()> {
+    if let Some(ref limit) = src.regex_size_limit {
+        builder.size_limit(parse_size(limit)?);
+    }
+    if let Some(ref limit) = src.dfa_size_limit {
+        builder.dfa_size_limit(parse_size(limit)?);
+    }
+    Ok(())
+}
 
-            process_tree.process(&tree, commit, &PathBuf::from(""), entry);
+/// Returns whether a regex has an uppercase letter that is matched literally, for `--smart-case`.
+/// The letters of escapes, of group names and of inline flags are skipped.
+fn has_uppercase_literal(pattern: &str) -> bool {
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                // Escapes like \p{Lu} and \x{4F} may have their argument in braces, or else a
+                // letter like \pL or hex digits like \x4F.
+                match chars.next() {
+                    Some('p' | 'P' | 'x' | 'u' | 'U') if chars.peek() == Some(&'{') => {
+                        chars.by_ref().find(|c| *c == '}');
+                    }
+                    Some('p' | 'P') => {
+                        chars.next();
+                    }
+                    Some('x' | 'u' | 'U') => {
+                        while chars.next_if(|c| c.is_ascii_hexdigit()).is_some() {}
+                    }
+                    _ => (),
+                }
+            }
+            '(' if chars.peek() == Some(&'?') => {
+                // Named groups like (?P<Name>...) and flags like (?U) or (?i:...).
+                chars.by_ref().find(|c| *c == '>' || *c == ':' || *c == ')');
+            }
+            c if c.is_uppercase() => return true,
+            _ => (),
         }
-        next_refs = next_refs
+    }
+    false
+}
+
+fn parse_binary_files(s: &str) -> Result<BinaryFiles> {
+    match s {
+        "without-match" => Ok(BinaryFiles::WithoutMatch),
+        "text" => Ok(BinaryFiles::Text),
+        "binary" => Ok(BinaryFiles::Binary),
+        _ => Err(anyhow!("Unknown binary files mode: {}", s)),
+    }
+}
+
+fn parse_commit_order(s: &str) -> Result<CommitOrder> {
+    match s {
+        "topo" => Ok(CommitOrder::Topological),
+        "date" => Ok(CommitOrder::Date),
+        "reverse-date" => Ok(CommitOrder::ReverseDate),
+        _ => Err(anyhow!("Unknown commit order: {}", s)),
+    }
+}
+
+/// Parses a size in bytes, with an optional binary suffix like `1M`.
+fn parse_size(s: &str) -> Result<usize> {
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm')) | Some((i, 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g')) | Some((i, 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(unit))
+        .ok_or_else(|| anyhow!("Invalid size: {:?}", s))
+}
+
+/// Returns the extensions of the named file types in [`FILE_TYPES`].
+fn type_extensions(types: &[String]) -> Result<Vec<OsString>> {
+    let mut extensions = vec![];
+    for name in types {
+        let (_, type_extensions) = FILE_TYPES
             .iter()
-            .map(|reference| reference.parent_ids())
-            .flatten()
-            .filter(|reference| !checked_commits.contains_key(reference))
-            .map(|id| repo.find_commit(id))
-            .collect::<std::result::Result<Vec<_>, git2::Error>>()?;
-
-        if settings.verbose {
-            eprintln!(
-                "[{}] {} Matches in {} files {} skipped blobs... Next round has {} refs...",
-                iter,
-                process_tree.all_matches.len(),
-                process_tree.walked,
-                process_tree.skipped_blobs,
-                next_refs.len()
-            );
-        }
-        iter += 1;
-        if next_refs.is_empty() || settings.depth.map(|depth| depth <= iter).unwrap_or(false) {
-            break;
+            .find(|(type_name, _)| type_name == name)
+            .ok_or_else(|| anyhow!("Unknown file type: {:?}; see --type-list", name))?;
+        extensions.extend(type_extensions.iter().map(OsString::from));
+    }
+    Ok(extensions)
+}
+
+fn build_globset(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(
+            GlobBuilder::new(glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| anyhow!("Error in glob compilation: {}", e))?,
+        );
+    }
+    Ok(builder.build()?)
+}
+
+fn build_ignore_file(paths: &[PathBuf]) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new("");
+    for path in paths {
+        if let Some(e) = builder.add(path) {
+            return Err(anyhow!("Error in ignore file {:?}: {}", path, e));
         }
     }
-    Ok(process_tree.all_matches)
+    Ok(builder.build()?)
+}
+
+/// Parses a date in RFC3339 or `YYYY-MM-DD` into seconds since the Unix epoch. The latter is
+/// interpreted in the local time zone, at the beginning of the day or at the end of it if
+/// `end_of_day` is set.
+fn parse_date(date: &str, end_of_day: bool) -> Result<i64> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(date) {
+        return Ok(time.timestamp());
+    }
+    let day = NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| {
+        anyhow!(
+            "Invalid date {:?}: expected RFC3339 (e.g. 2021-09-01T12:00:00+09:00) or YYYY-MM-DD",
+            date
+        )
+    })?;
+    let time = if end_of_day {
+        day.and_hms_opt(23, 59, 59)
+    } else {
+        day.and_hms_opt(0, 0, 0)
+    }
+    .ok_or_else(|| anyhow!("Invalid date {:?}", date))?;
+    Local
+        .from_local_datetime(&time)
+        .earliest()
+        .map(|time| time.timestamp())
+        .ok_or_else(|| anyhow!("Date {:?} does not exist in the local time zone", date))
 }
 
-fn process_file(
-    settings: &Settings,
-    commit: &Commit,
-    input: &[u8],
-    filepath: &Path,
-    visited: &mut bool,
-) -> Vec<MatchEntry> {
-    let mut ret = vec![];
+/// Returns the range of the match in the line, which is cut at the end of the line if the match
+/// spans several lines. It is empty for matches in binary files, which have no line.
+fn match_range(entry: &MatchEntry) -> (usize, usize) {
+    let end = (entry.end() - entry.line_start()).min(entry.line().len());
+    let start = (entry.start() - entry.line_start()).min(end);
+    (start, end)
+}
+
+/// The marker of the ends of lines cut by `--max-columns`.
+const ELLIPSIS: &str = "…";
 
-    // Non-utf8 files are not supported.
-    let input_str = if let Ok(utf8) = std::str::from_utf8(&input) {
-        utf8
+/// Returns the byte range of at most `max` characters of the line to print with `--max-columns`.
+/// It is the start of the line if the match from `start` to `end` is in it, or else a window
+/// centered on the match, or starting at the match if it is longer than the window.
+fn clip_window(line: &str, start: usize, end: usize, max: usize) -> (usize, usize) {
+    let len = line.chars().count();
+    if len <= max {
+        return (0, line.len());
+    }
+    let to_chars = |i: usize| line[..i].chars().count();
+    let (start_char, end_char) = (to_chars(start), to_chars(end));
+    let window_start = if end_char <= max {
+        0
+    } else if max <= end_char - start_char {
+        start_char
     } else {
-        return vec![];
+        ((start_char + end_char) / 2)
+            .saturating_sub(max / 2)
+            .min(len - max)
+    };
+    let to_bytes = |i: usize| {
+        line.char_indices()
+            .nth(i)
+            .map_or(line.len(), |(byte, _)| byte)
     };
+    (to_bytes(window_start), to_bytes(window_start + max))
+}
 
-    for found in settings.pattern.find_iter(&input_str) {
-        ret.push(MatchEntry {
-            commit: commit.id(),
-            path: filepath.to_path_buf(),
-            start: found.start(),
-            end: found.end(),
-        });
+/// Prints matches to the output. Matches are expected to arrive grouped by commit and then by file,
+/// so that commit headers are printed once and context lines of nearby matches in the same file
+/// can be merged rather than duplicated.
+struct Printer<'a> {
+    out: &'a mut dyn Write,
+    settings: &'a Settings,
+    output: &'a OutputSettings,
+    commit: Option<Oid>,
+    path: PathBuf,
+    /// The last line number printed in the current file.
+    last_printed: Option<usize>,
+    /// The last hunk printed in the current file, which is not printed again for the following
+    /// matches in it.
+    last_hunk: Option<Vec<String>>,
+    /// The line number of the last match and its trailing context, which is deferred until the
+    /// next match so that they can be merged.
+    after_context: (usize, Vec<String>),
+    /// The names of references to print after the commits they point to.
+    ref_names: HashMap<Oid, Vec<String>>,
+    /// The number of matches left out of the current file by `--max-per-file`, which is noted
+    /// after its trailing context.
+    more_matches: usize,
+}
+
+impl<'a> Printer<'a> {
+    fn new(out: &'a mut dyn Write, settings: &'a Settings, output: &'a OutputSettings) -> Self {
+        Self {
+            out,
+            settings,
+            output,
+            commit: None,
+            path: PathBuf::new(),
+            last_printed: None,
+            last_hunk: None,
+            after_context: (0, vec![]),
+            ref_names: HashMap::new(),
+            more_matches: 0,
+        }
+    }
 
-        // Very naive way to count line numbers. Assumes newlines would not be part of multibyte
-        // character, which is true for utf8 that is the only supported encoding in Rust anyway.
-        let mut line_number = 1;
-        let mut line_start = 0;
-        let mut line_end = 0;
-        for (i, c) in input.iter().enumerate() {
-            if *c == b'\n' {
-                line_number += 1;
-                if i < found.start() {
-                    line_start = (i + 1).min(input.len());
+    /// Returns the commit id with the names of references pointing to it, if any.
+    fn commit_label(&self, commit: Oid) -> String {
+        let id = self.output.commit_id(commit);
+        match self.ref_names.get(&commit) {
+            Some(names) => format!("{} ({})", id, names.join(", ")),
+            None => id,
+        }
+    }
+
+    /// The diff-style marker of a line of the match with `--diff-lines`: `+` for an added line,
+    /// `-` for a removed one and a space for others, including the context lines.
+    fn change_marker(&self, entry: &MatchEntry, context: bool) -> ColoredString {
+        let marker = match entry.change() {
+            None => return "".normal(),
+            Some(_) if context => " ".normal(),
+            Some(LineChange::Added) => "+".green(),
+            Some(LineChange::Removed) => "-".red(),
+            Some(LineChange::Unchanged) => " ".normal(),
+        };
+        if self.output.color_code {
+            marker
+        } else {
+            marker.clear()
+        }
+    }
+
+    fn print_match(&mut self, entry: &MatchEntry) -> Result<()> {
+        if self.output.json {
+            writeln!(self.out, "{}", match_to_json(entry, self.settings))?;
+            return Ok(());
+        }
+        if let Some(ref format) = self.output.format {
+            let text = if self.output.only_matching && !self.settings.invert_match {
+                let (start, end) = match_range(entry);
+                &entry.line()[start..end]
+            } else {
+                entry.line()
+            };
+            let text = self.replace(text, false);
+            writeln!(self.out, "{}", format.render(entry, &text, self.output))?;
+            return Ok(());
+        }
+
+        if self.commit != Some(entry.commit()) || self.path != entry.path() {
+            self.flush_after_context(usize::MAX)?;
+            self.print_more_matches()?;
+            if self.commit != Some(entry.commit()) && self.output.output_grouping {
+                if self.output.color_code {
+                    writeln!(
+                        self.out,
+                        "\ncommit {}:",
+                        self.commit_label(entry.commit()).bright_blue()
+                    )?;
+                } else {
+                    writeln!(self.out, "\ncommit {}:", self.commit_label(entry.commit()))?;
                 }
-                if found.end() <= i {
-                    line_end = (i as usize).max(line_start);
-                    break;
+            }
+            if self.output.group_by_file && self.path != entry.path() {
+                if self.output.color_code {
+                    writeln!(self.out, "\n{}:", entry.path().to_string_lossy().green())?;
+                } else {
+                    writeln!(self.out, "\n{}:", entry.path().to_string_lossy())?;
+                }
+            }
+            self.commit = Some(entry.commit());
+            self.path = entry.path().to_path_buf();
+            self.last_printed = None;
+            self.last_hunk = None;
+            // Binary and name matches have the path in themselves.
+            if self.output.heading && !entry.binary() && !self.settings.name_search {
+                if self.output.color_code {
+                    writeln!(self.out, "  {}", self.path.to_string_lossy().green())?;
+                } else {
+                    writeln!(self.out, "  {}", self.path.to_string_lossy())?;
                 }
             }
         }
+        self.flush_after_context(entry.line_number())?;
+
+        if entry.binary() {
+            return self.print_binary_match();
+        }
+        if self.settings.name_search {
+            return self.print_name_match(entry);
+        }
+        // The line was printed with the earlier matches in it.
+        if self.output.highlight_only && self.last_printed == Some(entry.line_number()) {
+            return Ok(());
+        }
 
-        if settings.color_code {
-            if settings.output_grouping && !*visited {
-                println!("\ncommit {}:", commit.id().to_string().bright_blue());
-                *visited = true;
+        let before_start = entry.line_number() - entry.context_before().len();
+        let context = 0 < self.settings.before_context || 0 < self.settings.after_context;
+        if let Some(last) = self.last_printed {
+            if context && last + 1 < before_start {
+                self.print_context_separator()?;
+            }
+        }
+        for (i, line) in entry.context_before().iter().enumerate() {
+            let line_number = before_start + i;
+            if self.last_printed < Some(line_number) {
+                let line = self.clip_context(line);
+                let line = format!("{}{}", self.change_marker(entry, true), line);
+                self.print_line(line_number, None, '-', &line)?;
             }
-            let mut content = if line_start < found.start() {
-                input_str[line_start..found.start()].to_owned()
+        }
+
+        let (start, end) = match_range(entry);
+        let column = if self.output.column {
+            Some(entry.column())
+        } else {
+            None
+        };
+        // A multiline match is printed line by line, with the column only on the first one.
+        if self.output.replace.is_some() {
+            let text = if self.output.only_matching {
+                &entry.line()[start..end]
             } else {
-                "".to_owned()
+                entry.line()
             };
-            if found.start() < found.end() {
-                content += &input_str[found.start()..found.end()]
-                    .red()
-                    .bold()
-                    .to_string();
+            let replaced = self.replace(text, self.output.color_code).into_owned();
+            for (i, line) in replaced.split('\n').enumerate() {
+                let column = column.filter(|_| i == 0);
+                let line = format!("{}{}", self.change_marker(entry, false), line);
+                self.print_line(entry.line_number() + i, column, ':', &line)?;
+            }
+        } else {
+            let mut line_start = 0;
+            for (i, line) in entry.line().split('\n').enumerate() {
+                let line_end = line_start + line.len();
+                let content = self.highlight(
+                    line,
+                    start.clamp(line_start, line_end) - line_start,
+                    end.clamp(line_start, line_end) - line_start,
+                );
+                line_start = line_end + 1;
+                let column = column.filter(|_| i == 0);
+                let content = format!("{}{}", self.change_marker(entry, false), content);
+                self.print_line(entry.line_number() + i, column, ':', &content)?;
+            }
+        }
+        if let Some(hunk) = entry.hunk() {
+            if hunk.is_empty() || self.last_hunk.as_deref() != Some(hunk) {
+                self.print_hunk(hunk)?;
+                self.last_hunk = Some(hunk.to_vec());
+            }
+        }
+        self.last_printed = Some(entry.end_line_number());
+        self.after_context = (entry.end_line_number(), entry.context_after().to_vec());
+        self.more_matches = entry.more_matches();
+        Ok(())
+    }
+
+    /// Formats a line with the matched part from `start` to `end` in it, which is highlighted
+    /// or the only part printed depending on the options.
+    fn highlight(&self, line: &str, start: usize, end: usize) -> String {
+        if let Some(max) = self.output.max_columns {
+            let (window_start, window_end) = clip_window(line, start, end, max);
+            if (window_start, window_end) != (0, line.len()) {
+                let clamp = |i: usize| i.clamp(window_start, window_end) - window_start;
+                let content =
+                    self.highlight_whole(&line[window_start..window_end], clamp(start), clamp(end));
+                // Only the matched part is printed with -o, so the ends are cut if it is.
+                let (first, last) = if self.output.only_matching && !self.settings.invert_match {
+                    (start, end)
+                } else {
+                    (0, line.len())
+                };
+                let marker = |cut: bool| if cut { ELLIPSIS } else { "" };
+                return format!(
+                    "{}{}{}",
+                    marker(first < window_start),
+                    content,
+                    marker(window_end < last)
+                );
+            }
+        }
+        self.highlight_whole(line, start, end)
+    }
+
+    /// Cuts a context line to `max_columns` from the start.
+    fn clip_context<'l>(&self, line: &'l str) -> Cow<'l, str> {
+        match self.output.max_columns {
+            Some(max) => match clip_window(line, 0, 0, max) {
+                (_, end) if end < line.len() => Cow::Owned(format!("{}{}", &line[..end], ELLIPSIS)),
+                _ => Cow::Borrowed(line),
+            },
+            None => Cow::Borrowed(line),
+        }
+    }
+
+    fn highlight_whole(&self, line: &str, start: usize, end: usize) -> String {
+        // Inverted matches have nothing matched in the line, so they are printed as a whole.
+        if self.output.only_matching && !self.settings.invert_match {
+            if self.output.color_code {
+                line[start..end].red().bold().to_string()
+            } else {
+                line[start..end].to_owned()
+            }
+        } else if self.output.color_code
+            && !self.settings.invert_match
+            && self.output.highlight_only
+        {
+            let mut highlighted = String::new();
+            let mut last = 0;
+            for found in self.settings.pattern.find_iter(line) {
+                highlighted.push_str(&line[last..found.start()]);
+                highlighted.push_str(&found.as_str().red().bold().to_string());
+                last = found.end();
+            }
+            highlighted.push_str(&line[last..]);
+            highlighted
+        } else if self.output.color_code && !self.settings.invert_match {
+            format!(
+                "{}{}{}",
+                &line[..start],
+                line[start..end].red().bold(),
+                &line[end..]
+            )
+        } else {
+            line.to_owned()
+        }
+    }
+
+    /// Replaces the matches in `text` with `--replace` if given, highlighting the replacements
+    /// if `color`.
+    fn replace<'t>(&self, text: &'t str, color: bool) -> Cow<'t, str> {
+        let template = match self.output.replace {
+            Some(ref template) if !self.settings.invert_match => template,
+            _ => return Cow::Borrowed(text),
+        };
+        self.settings
+            .pattern
+            .replace_all(text, |captures: &Captures| {
+                let mut replaced = String::new();
+                captures.expand(template, &mut replaced);
+                if color {
+                    replaced.red().bold().to_string()
+                } else {
+                    replaced
+                }
+            })
+    }
+
+    /// Prints trailing context lines of the previous match that come before `until` and are not
+    /// printed yet.
+    fn flush_after_context(&mut self, until: usize) -> Result<()> {
+        let (line_number, lines) = std::mem::take(&mut self.after_context);
+        for (i, line) in lines.iter().enumerate() {
+            let context_line = line_number + 1 + i;
+            if until <= context_line {
+                break;
+            }
+            if self.last_printed < Some(context_line) {
+                let line = self.clip_context(line);
+                self.print_line(context_line, None, '-', &line)?;
+                self.last_printed = Some(context_line);
+            }
+        }
+        Ok(())
+    }
+
+    fn print_more_matches(&mut self) -> Result<()> {
+        let indent = if self.output.output_grouping {
+            "  "
+        } else {
+            ""
+        };
+        match std::mem::take(&mut self.more_matches) {
+            0 => (),
+            1 => writeln!(self.out, "{}... (1 more match)", indent)?,
+            more => writeln!(self.out, "{}... ({} more matches)", indent, more)?,
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.flush_after_context(usize::MAX)?;
+        self.print_more_matches()
+    }
+
+    /// Prints a line of a file, either a matched line (`separator == ':'`) or a context line
+    /// (`separator == '-'`).
+    fn print_line(
+        &mut self,
+        line_number: usize,
+        column: Option<usize>,
+        separator: char,
+        content: &str,
+    ) -> Result<()> {
+        let commit = self.commit_label(self.commit.unwrap_or_else(Oid::zero));
+        let location = match column {
+            Some(column) => format!("{}:{}", line_number, column),
+            None => line_number.to_string(),
+        };
+        if self.output.group_by_file {
+            if self.output.color_code {
+                let location = format!("{}{}", location, separator).bright_yellow();
+                writeln!(
+                    self.out,
+                    "  {} {} {}",
+                    commit.bright_blue(),
+                    location,
+                    content
+                )?;
+            } else {
+                writeln!(
+                    self.out,
+                    "  {} {}{} {}",
+                    commit, location, separator, content
+                )?;
             }
-            if found.end() < line_end {
-                content += &input_str[found.end()..line_end];
+        } else if self.output.heading {
+            if self.output.color_code {
+                let location = format!("{}{}", location, separator).bright_yellow();
+                writeln!(self.out, "    {} {}", location, content)?;
+            } else {
+                writeln!(self.out, "    {}{} {}", location, separator, content)?;
+            }
+        } else if let Some(ref field_separator) = self.output.field_separator {
+            let (commit, path, location) = if self.output.color_code {
+                (
+                    commit.bright_blue().to_string(),
+                    self.path.to_string_lossy().green().to_string(),
+                    location.bright_yellow().to_string(),
+                )
+            } else {
+                (commit, self.path.to_string_lossy().into_owned(), location)
+            };
+            let line = [path, location, content.to_owned()].join(field_separator);
+            if !self.output.output_grouping {
+                writeln!(self.out, "{}{}{}", commit, field_separator, line)?;
+            } else {
+                writeln!(self.out, "  {}", line)?;
             }
+        } else if self.output.color_code {
             let line = format!(
                 "{} {} {}",
-                filepath.to_string_lossy().green(),
-                &format!("({}):", line_number).bright_yellow(),
-                &content
+                self.path.to_string_lossy().green(),
+                &format!("({}){}", location, separator).bright_yellow(),
+                content
             );
-            if !settings.output_grouping {
-                println!("{} {}", commit.id().to_string().bright_blue(), line);
+            if !self.output.output_grouping {
+                writeln!(self.out, "{} {}", commit.bright_blue(), line)?;
             } else {
-                println!("  {}", line);
+                writeln!(self.out, "  {}", line)?;
             }
         } else {
-            if settings.output_grouping && !*visited {
-                println!("\ncommit {}:", commit.id());
-                *visited = true;
-            }
             let line = format!(
-                "{}({}): {}",
-                filepath.to_string_lossy(),
-                line_number,
-                &input_str[line_start..line_end]
+                "{}({}){} {}",
+                self.path.to_string_lossy(),
+                location,
+                separator,
+                content
             );
-            if !settings.output_grouping {
-                println!("{} {}", commit.id(), line);
+            if !self.output.output_grouping {
+                writeln!(self.out, "{} {}", commit, line)?;
+            } else {
+                writeln!(self.out, "  {}", line)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells that the current file matches, with no line since it is binary.
+    fn print_binary_match(&mut self) -> Result<()> {
+        let commit = self.commit_label(self.commit.unwrap_or_else(Oid::zero));
+        let path = if self.output.color_code {
+            self.path.to_string_lossy().green().to_string()
+        } else {
+            self.path.to_string_lossy().into_owned()
+        };
+        self.print_after_commit(commit, &format!("Binary file {} matches", path))
+    }
+
+    /// Prints the path of a file whose name matches, highlighting the match in it.
+    fn print_name_match(&mut self, entry: &MatchEntry) -> Result<()> {
+        let commit = self.commit_label(entry.commit());
+        let (start, end) = match_range(entry);
+        let path = entry.line();
+        let path = if self.output.color_code {
+            format!(
+                "{}{}{}",
+                path[..start].green(),
+                path[start..end].red().bold(),
+                path[end..].green()
+            )
+        } else {
+            path.to_owned()
+        };
+        self.print_after_commit(commit, &path)
+    }
+
+    /// Prints a line under the heading of its commit, or after the commit and the separator of
+    /// `--field-separator` or a space without grouping, like the matched lines.
+    fn print_after_commit(&mut self, commit: String, line: &str) -> Result<()> {
+        if self.output.output_grouping {
+            writeln!(self.out, "  {}", line)?;
+            return Ok(());
+        }
+        let commit = if self.output.color_code {
+            commit.bright_blue().to_string()
+        } else {
+            commit
+        };
+        let separator = self.output.field_separator.as_deref().unwrap_or(" ");
+        writeln!(self.out, "{}{}{}", commit, separator, line)?;
+        Ok(())
+    }
+
+    /// Prints the hunk of a match below it, indented further than the lines.
+    fn print_hunk(&mut self, hunk: &[String]) -> Result<()> {
+        let indent = if self.output.output_grouping {
+            "      "
+        } else {
+            "    "
+        };
+        if hunk.is_empty() {
+            writeln!(self.out, "{}(unchanged from the parent)", indent)?;
+        }
+        for (i, line) in hunk.iter().enumerate() {
+            if !self.output.color_code {
+                writeln!(self.out, "{}{}", indent, line)?;
+            } else if i == 0 {
+                writeln!(self.out, "{}{}", indent, line.cyan())?;
+            } else if line.starts_with('+') {
+                writeln!(self.out, "{}{}", indent, line.green())?;
+            } else if line.starts_with('-') {
+                writeln!(self.out, "{}{}", indent, line.red())?;
             } else {
-                println!("  {}", line);
+                writeln!(self.out, "{}{}", indent, line)?;
             }
         }
+        Ok(())
+    }
+
+    fn print_context_separator(&mut self) -> Result<()> {
+        let separator = &self.output.context_separator;
+        if self.output.heading {
+            writeln!(self.out, "    {}", separator)?;
+        } else if self.output.output_grouping {
+            writeln!(self.out, "  {}", separator)?;
+        } else {
+            writeln!(self.out, "{}", separator)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the pattern of the command line arguments after the program name.
+    fn pattern(args: &[&str], patterns: &[&str]) -> Regex {
+        let opt = Opt::from_iter(std::iter::once("rust-gitgrep").chain(args.iter().copied()));
+        let patterns: Vec<_> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        build_pattern(&opt, &patterns).unwrap()
+    }
+
+    #[test]
+    fn word_regexp_matches_whole_words() {
+        let regex = pattern(&["-w", "foo"], &["foo"]);
+        assert!(regex.is_match("foo bar"));
+        assert!(regex.is_match("bar.foo()"));
+        assert!(!regex.is_match("foobar"));
+        assert!(!regex.is_match("barfoo"));
+
+        let regex = pattern(&["-w", "-i", "foo"], &["foo"]);
+        assert!(regex.is_match("FOO bar"));
+        assert!(!regex.is_match("FOOBAR"));
+
+        // The group keeps the alternation inside the boundaries.
+        let regex = pattern(&["-w", "foo"], &["foo|bar"]);
+        assert!(regex.is_match("a bar"));
+        assert!(!regex.is_match("foox"));
+        assert!(!regex.is_match("xbar"));
+        let regex = pattern(&["-w", "foo", "-e", "bar"], &["foo", "bar"]);
+        assert!(!regex.is_match("foox xbar"));
+    }
+
+    #[test]
+    fn line_regexp_matches_whole_lines() {
+        let regex = pattern(&["-x", "foo"], &["foo"]);
+        assert!(regex.is_match("foo"));
+        assert!(regex.is_match("a\nfoo\nb"));
+        assert!(!regex.is_match("foo "));
+        assert!(!regex.is_match(" foo"));
+        assert!(!regex.is_match("foo bar"));
+
+        let regex = pattern(&["-x", "foo"], &["foo|bar"]);
+        assert!(regex.is_match("bar"));
+        assert!(!regex.is_match("foo bar"));
+
+        // Only blank lines match the empty pattern, not the lines with spaces.
+        let regex = pattern(&["-x", "foo"], &[""]);
+        let found: Vec<_> = regex.find_iter("a\n\n  \nb").map(|m| m.start()).collect();
+        assert_eq!(found, [2]);
+        let regex = pattern(&["-x", "foo"], &[r"\s*"]);
+        assert!(regex.is_match("  "));
+        assert!(!regex.is_match(" a "));
+    }
+
+    /// Commits a file of the content to a new repository.
+    fn repo_with_file(content: &str) -> tempfile::TempDir {
+        let dir = tempfile::TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        std::fs::write(dir.path().join("a.rs"), content).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("a.rs")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "a", &tree, &[])
+            .unwrap();
+        dir
+    }
+
+    /// Commits a file of the content to a new repository, and returns the matches in it.
+    fn search_file(content: &str, settings: impl FnOnce(PathBuf) -> Settings) -> Vec<MatchEntry> {
+        let dir = repo_with_file(content);
+        GitGrep::new(settings(dir.path().to_path_buf()))
+            .search()
+            .unwrap()
+    }
+
+    #[test]
+    fn json_offsets_after_a_multibyte_character() {
+        let settings = |repo| Settings {
+            extensions: HashSet::new(),
+            ..Settings::new(Regex::new(r"\((f)(o+)\)").unwrap(), repo)
+        };
+        let matches = search_file("x\n// é (foo) bar\n", settings);
+        let settings = settings(PathBuf::new());
+        let json = match_to_json(&matches[0], &settings);
+        // The "é" is two bytes, so the byte columns are one after the character columns.
+        assert_eq!(json["line_number"], 2);
+        assert_eq!(json["char_line"], 2);
+        assert_eq!(json["char_end_line"], 2);
+        assert_eq!(json["byte_start"], 8);
+        assert_eq!(json["byte_end"], 13);
+        assert_eq!(json["byte_column"], 7);
+        assert_eq!(json["byte_end_column"], 12);
+        assert_eq!(json["column"], 6);
+        assert_eq!(json["char_column"], 6);
+        assert_eq!(json["char_end_column"], 11);
+        let submatches = json["submatches"].as_array().unwrap();
+        assert_eq!(submatches.len(), 2);
+        assert_eq!(submatches[0]["text"], "f");
+        assert_eq!(submatches[0]["byte_column"], 8);
+        assert_eq!(submatches[0]["char_column"], 7);
+        assert_eq!(submatches[1]["text"], "oo");
+        assert_eq!(submatches[1]["byte_end_column"], 11);
+        assert_eq!(submatches[1]["char_end_column"], 10);
+    }
+
+    #[test]
+    fn interactive_conflicts_with_the_other_outputs() {
+        for option in [
+            "--count-commits",
+            "-L",
+            "--commits-only",
+            "--summary-per-commit",
+        ] {
+            let args = ["rust-gitgrep", "--interactive", option, "foo"];
+            assert!(
+                Opt::from_iter_safe(args).is_err(),
+                "{} was accepted",
+                option
+            );
+        }
+        assert!(Opt::from_iter_safe(["rust-gitgrep", "--interactive", "foo"]).is_ok());
+    }
+
+    #[test]
+    fn format_abbreviates_commits_by_abbrev() {
+        let matches = search_file("foo\n", |repo| Settings {
+            extensions: HashSet::new(),
+            ..Settings::new(Regex::new("foo").unwrap(), repo)
+        });
+        let id = matches[0].commit().to_string();
+        let render = |args: &[&str]| {
+            let args = ["rust-gitgrep", "--format", "{commit} {short_commit}"]
+                .iter()
+                .chain(args)
+                .chain(&["foo"]);
+            let config: Config = Opt::from_iter(args).try_into().unwrap();
+            let format = config.output.format.as_ref().unwrap();
+            format.render(&matches[0], "", &config.output)
+        };
+        assert_eq!(render(&[]), format!("{} {}", id, &id[..7]));
+        assert_eq!(render(&["--abbrev"]), format!("{} {}", &id[..8], &id[..8]));
+        assert_eq!(
+            render(&["--abbrev=12"]),
+            format!("{} {}", &id[..12], &id[..12])
+        );
+    }
+
+    #[test]
+    fn commits_are_listed_with_short_ids() {
+        let dir = repo_with_file("foo\n");
+        let repo = dir.path().to_str().unwrap();
+        let id = Repository::open(repo)
+            .unwrap()
+            .head()
+            .unwrap()
+            .target()
+            .unwrap()
+            .to_string();
+        let print = |args: &[&str]| {
+            let tail = ["foo", repo];
+            let args = ["rust-gitgrep", "--color", "never"]
+                .iter()
+                .chain(args)
+                .chain(&tail);
+            let config: Config = Opt::from_iter(args).try_into().unwrap();
+            let mut out = vec![];
+            print_commits(&mut out, &[GitGrep::new(config.settings)], &config.output).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        for mode in ["--commits-only", "--summary-per-commit"] {
+            let first_word =
+                |out: String| out.split_whitespace().next().unwrap_or_default().to_owned();
+            assert_eq!(first_word(print(&[mode])), id[..7]);
+            assert_eq!(first_word(print(&[mode, "--abbrev"])), id[..8]);
+            assert_eq!(first_word(print(&[mode, "--abbrev=12"])), id[..12]);
+        }
+        assert_eq!(
+            print(&["--summary-per-commit"]),
+            format!("{}  a  (1 file, 1 match)\n", &id[..7])
+        );
     }
 
-    ret
+    #[test]
+    fn size_limits_apply_to_line_filters() {
+        let patterns = ["a{1000}".to_owned()];
+        let opt = Opt::from_iter(["rust-gitgrep", "--regex-size-limit", "1K", "foo"]);
+        assert!(build_line_filters(&opt, &patterns, "--and").is_err());
+        let opt = Opt::from_iter(["rust-gitgrep", "foo"]);
+        assert!(build_line_filters(&opt, &patterns, "--and").is_ok());
+    }
+
+    #[test]
+    fn field_separator_is_used_with_and_without_color() {
+        let ansi = Regex::new("\x1b\\[[0-9;]*m").unwrap();
+        let print = |content: &str, args: &[&str]| {
+            let dir = repo_with_file(content);
+            let repo = dir.path().to_str().unwrap();
+            let printed: Vec<_> = ["never", "always"]
+                .iter()
+                .map(|color| {
+                    let tail = ["--color", color, "foo|a", repo];
+                    let args = ["rust-gitgrep", "-g", "--field-separator", "|"]
+                        .iter()
+                        .chain(args)
+                        .chain(&tail);
+                    let config: Config = Opt::from_iter(args).try_into().unwrap();
+                    colored::control::set_override(config.output.color_code);
+                    let mut out = vec![];
+                    let mut printer = Printer::new(&mut out, &config.settings, &config.output);
+                    for entry in GitGrep::new(config.settings.clone()).search().unwrap() {
+                        printer.print_match(&entry).unwrap();
+                    }
+                    printer.finish().unwrap();
+                    drop(printer);
+                    String::from_utf8(out).unwrap()
+                })
+                .collect();
+            colored::control::unset_override();
+            assert_ne!(printed[0], printed[1]);
+            assert_eq!(ansi.replace_all(&printed[1], ""), printed[0]);
+            printed[0].clone()
+        };
+        let binary = print("foo\0\n", &["--binary-files", "binary"]);
+        assert!(
+            binary.ends_with("|Binary file a.rs matches\n"),
+            "{:?}",
+            binary
+        );
+        let name = print("foo\n", &["--name-search"]);
+        assert!(name.ends_with("|a.rs\n"), "{:?}", name);
+    }
+
+    #[test]
+    fn no_patterns_match_nothing() {
+        for args in [&["foo"][..], &["-x", "foo"], &["-w", "foo"]] {
+            let regex = pattern(args, &[]);
+            for line in ["", "foo", " ", "\n"] {
+                assert!(!regex.is_match(line), "{:?} matched {:?}", args, line);
+            }
+        }
+    }
 }
@@ -0,0 +1,205 @@
+//! An interactive browser of matches given by `--interactive`. It lists the commits with the
+//! number of matches in each, and shows the matched lines of the commit opened from the list.
+
+use crate::OutputSettings;
+use anyhow::{anyhow, Result};
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType},
+};
+use git2::Oid;
+use rust_gitgrep::MatchEntry;
+use std::io::{self, IsTerminal, Write};
+
+const HELP_LIST: &str = "Up/Down: select  Enter: open  q: quit";
+const HELP_COMMIT: &str = "Up/Down: scroll  Esc: back  q: quit";
+
+/// The position in a list of lines, as the selected line and the first line on the screen.
+#[derive(Default)]
+struct View {
+    selected: usize,
+    offset: usize,
+}
+
+impl View {
+    fn move_by(&mut self, delta: isize, len: usize) {
+        let last = len.saturating_sub(1) as isize;
+        self.selected = (self.selected as isize + delta).clamp(0, last) as usize;
+    }
+
+    /// Scrolls so that the selected line is within `height` lines from the top.
+    fn follow(&mut self, height: usize) {
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if self.offset + height <= self.selected {
+            self.offset = self.selected + 1 - height;
+        }
+    }
+}
+
+struct Browser<'a> {
+    output: &'a OutputSettings,
+    /// Matches grouped by commit, in the order they were found.
+    commits: Vec<(Oid, Vec<MatchEntry>)>,
+    list: View,
+    /// The commit opened from the list and the view of its matches, if any.
+    opened: Option<(usize, View)>,
+}
+
+/// Shows the matches in the terminal until the user quits.
+pub fn browse(matches: Vec<MatchEntry>, output: &OutputSettings) -> Result<()> {
+    if !io::stdout().is_terminal() {
+        return Err(anyhow!("--interactive needs the output to be a terminal"));
+    }
+
+    let mut commits: Vec<(Oid, Vec<MatchEntry>)> = vec![];
+    for entry in matches {
+        match commits.last_mut() {
+            Some((commit, entries)) if *commit == entry.commit() => entries.push(entry),
+            _ => commits.push((entry.commit(), vec![entry])),
+        }
+    }
+    let mut browser = Browser {
+        output,
+        commits,
+        list: View::default(),
+        opened: None,
+    };
+
+    let mut stdout = io::stdout();
+    terminal::enable_raw_mode()?;
+    execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+    let result = browser.run(&mut stdout);
+    // Restore the terminal even if drawing failed, or the shell would be left in raw mode.
+    execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+    result
+}
+
+impl<'a> Browser<'a> {
+    fn run(&mut self, out: &mut impl Write) -> Result<()> {
+        loop {
+            let (width, height) = terminal::size()?;
+            // The last row is for the help.
+            let rows = (height as usize).saturating_sub(1).max(1);
+            self.draw(out, width as usize, rows)?;
+
+            let key = match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => key,
+                _ => continue,
+            };
+            let len = self.lines().len();
+            let opened = self.opened.is_some();
+            let view = match self.opened {
+                Some((_, ref mut view)) => view,
+                None => &mut self.list,
+            };
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(())
+                }
+                KeyCode::Up | KeyCode::Char('k') => view.move_by(-1, len),
+                KeyCode::Down | KeyCode::Char('j') => view.move_by(1, len),
+                KeyCode::PageUp => view.move_by(-(rows as isize), len),
+                KeyCode::PageDown => view.move_by(rows as isize, len),
+                KeyCode::Home => view.selected = 0,
+                KeyCode::End => view.move_by(len as isize, len),
+                KeyCode::Enter | KeyCode::Right if !opened && 0 < len => {
+                    self.opened = Some((self.list.selected, View::default()))
+                }
+                KeyCode::Esc | KeyCode::Left | KeyCode::Backspace => self.opened = None,
+                _ => (),
+            }
+        }
+    }
+
+    /// The lines of the current view, which is either the list of commits or the matches in the
+    /// opened commit.
+    fn lines(&self) -> Vec<String> {
+        match self.opened {
+            None => self
+                .commits
+                .iter()
+                .map(|(commit, matches)| {
+                    let files = matches
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, entry)| *i == 0 || matches[i - 1].path() != entry.path())
+                        .count();
+                    format!(
+                        "{} {} matches in {} files",
+                        self.output.commit_id(*commit),
+                        matches.len(),
+                        files
+                    )
+                })
+                .collect(),
+            Some((index, _)) => self.commits[index]
+                .1
+                .iter()
+                .map(|entry| {
+                    let path = entry.path().to_string_lossy();
+                    if entry.binary() {
+                        format!("Binary file {} matches", path)
+                    } else {
+                        format!("{}({}): {}", path, entry.line_number(), entry.line())
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    fn draw(&mut self, out: &mut impl Write, width: usize, rows: usize) -> Result<()> {
+        let lines = self.lines();
+        let (view, help) = match self.opened {
+            Some((_, ref mut view)) => (view, HELP_COMMIT),
+            None => (&mut self.list, HELP_LIST),
+        };
+        view.follow(rows);
+
+        queue!(out, terminal::Clear(ClearType::All))?;
+        for (row, (i, line)) in lines
+            .iter()
+            .enumerate()
+            .skip(view.offset)
+            .take(rows)
+            .enumerate()
+        {
+            // Tabs would move the cursor past the truncated width.
+            let line: String = line.replace('\t', "    ").chars().take(width).collect();
+            queue!(out, cursor::MoveTo(0, row as u16))?;
+            if i == view.selected {
+                queue!(
+                    out,
+                    SetAttribute(Attribute::Reverse),
+                    Print(line),
+                    SetAttribute(Attribute::Reset)
+                )?;
+            } else {
+                queue!(out, Print(line))?;
+            }
+        }
+        let status = match self.opened {
+            Some((index, _)) => format!(
+                "commit {}  {}",
+                self.output.commit_id(self.commits[index].0),
+                help
+            ),
+            None => format!("{} commits  {}", self.commits.len(), help),
+        };
+        let status: String = status.chars().take(width).collect();
+        queue!(
+            out,
+            cursor::MoveTo(0, rows as u16),
+            SetAttribute(Attribute::Bold),
+            Print(status),
+            SetAttribute(Attribute::Reset)
+        )?;
+        out.flush()?;
+        Ok(())
+    }
+}